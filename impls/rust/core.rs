@@ -1,26 +1,302 @@
+use std::cell::RefCell;
 use std::fs::File;
 use std::io::Read;
 use std::rc::Rc;
-use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
-use crate::printer::pr_seq;
-use crate::reader::read_str;
-use crate::types::MalErr::ErrMalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{MalArgs, MalRet, MalVal, _assoc, _dissoc, atom, error, func, hash_map};
-
-macro_rules! fn_t_int_int {
-    ($ret:ident, $fn:expr) => {{
-        |a: MalArgs| match (a[0].clone(), a[1].clone()) {
-            (Int(a0), Int(a1)) => Ok($ret($fn(a0, a1))),
-            _ => error("expecting (int,int) args"),
+use itertools::Itertools;
+use regex::{Captures, Regex};
+
+use crate::env::{env_get, Env};
+use crate::printer::{PrintLimits, DEFAULT_RIGHT_MARGIN};
+use crate::reader::{inst_ms, read_all, read_str_in};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{Signed, ToPrimitive, Zero};
+
+use self::NumPair::{BigInts, Floats, Ints, Ratios};
+use crate::types::MalErr::{ErrMalVal, ErrString};
+use crate::types::MalVal::{
+    Atom, Bool, Char, Float, Func, Hash, Int, Keyword, LazySeq, List, MalFunc, Nil, Ratio, Set,
+    Str, Sym, Tagged, Vector,
+};
+use crate::types::{
+    _assoc, _dissoc, atom, big_int, error, func, hash_map, hash_set, int_from_str, lazy_cons,
+    lazy_native, lazy_seq, ratio, regex, seq_rest, typed_error, Arity, LazyState, MalArgs, MalErr,
+    MalRet, MalSeq, MalSet, MalVal, NativeFn,
+};
+
+// Raised as {:type :arithmetic :message s}
+fn arithmetic_error(s: &str) -> MalRet {
+    Err(typed_error("arithmetic", s))
+}
+
+// Both operands promoted to the widest numeric type of the pair
+enum NumPair {
+    Ints(i64, i64),
+    BigInts(BigInt, BigInt),
+    Ratios(BigRational, BigRational),
+    Floats(f64, f64),
+}
+
+fn to_bigint(a: &MalVal) -> Result<BigInt, MalErr> {
+    match a {
+        Int(i) => Ok(BigInt::from(*i)),
+        MalVal::BigInt(b) => Ok((**b).clone()),
+        _ => Err(typed_error("type", "expecting (number,number) args")),
+    }
+}
+
+fn to_ratio(a: &MalVal) -> Result<BigRational, MalErr> {
+    match a {
+        Ratio(r) => Ok((**r).clone()),
+        _ => Ok(BigRational::from_integer(to_bigint(a)?)),
+    }
+}
+
+fn to_f64(a: &MalVal) -> Result<f64, MalErr> {
+    match a {
+        Int(i) => Ok(*i as f64),
+        MalVal::BigInt(b) => Ok(b.to_f64().unwrap_or(f64::NAN)),
+        Ratio(r) => match (r.numer().to_f64(), r.denom().to_f64()) {
+            (Some(n), Some(d)) => Ok(n / d),
+            _ => Ok(f64::NAN),
+        },
+        Float(f) => Ok(*f),
+        _ => Err(typed_error("type", "expecting (number,number) args")),
+    }
+}
+
+fn num_pair(a0: &MalVal, a1: &MalVal) -> Result<NumPair, MalErr> {
+    match (a0, a1) {
+        (Int(i), Int(j)) => Ok(Ints(*i, *j)),
+        (Float(_), _) | (_, Float(_)) => Ok(Floats(to_f64(a0)?, to_f64(a1)?)),
+        (Ratio(_), _) | (_, Ratio(_)) => Ok(Ratios(to_ratio(a0)?, to_ratio(a1)?)),
+        _ => Ok(BigInts(to_bigint(a0)?, to_bigint(a1)?)),
+    }
+}
+
+macro_rules! num_op {
+    ($name:ident, $op:tt, $checked:ident) => {
+        fn $name(a0: &MalVal, a1: &MalVal) -> MalRet {
+            Ok(match num_pair(a0, a1)? {
+                Ints(i, j) => match i.$checked(j) {
+                    Some(r) => Int(r),
+                    None => big_int(BigInt::from(i) $op BigInt::from(j)),
+                },
+                BigInts(i, j) => big_int(i $op j),
+                Ratios(r, s) => ratio(r $op s),
+                Floats(x, y) => Float(x $op y),
+            })
         }
-    }};
+    };
+}
+
+num_op!(add, +, checked_add);
+num_op!(sub, -, checked_sub);
+num_op!(mul, *, checked_mul);
+
+macro_rules! cmp_op {
+    ($name:ident, $op:tt) => {
+        fn $name(a0: &MalVal, a1: &MalVal) -> Result<bool, MalErr> {
+            Ok(match num_pair(a0, a1)? {
+                Ints(i, j) => i $op j,
+                BigInts(i, j) => i $op j,
+                Ratios(r, s) => r $op s,
+                Floats(x, y) => x $op y,
+            })
+        }
+    };
+}
+
+cmp_op!(lt, <);
+cmp_op!(le, <=);
+cmp_op!(gt, >);
+cmp_op!(ge, >=);
+cmp_op!(num_eq, ==);
+
+// (+) is 0, (+ x) is x, (+ x y z) is (+ (+ x y) z); the identity is
+// combined with the first argument so that it still gets type checked
+fn fold_num(a: &MalArgs, identity: MalVal, op: fn(&MalVal, &MalVal) -> MalRet) -> MalRet {
+    a.iter().try_fold(identity, |acc, x| op(&acc, x))
+}
+
+// (- x) and (/ x) apply the operation to the identity and x
+fn fold_num_inverse(a: &MalArgs, identity: MalVal, op: fn(&MalVal, &MalVal) -> MalRet) -> MalRet {
+    match a.len() {
+        1 => op(&identity, &a[0]),
+        _ => a[1..].iter().try_fold(a[0].clone(), |acc, x| op(&acc, x)),
+    }
+}
+
+// (< a b c) holds when every adjacent pair is in order
+fn chain_cmp(a: &MalArgs, cmp: fn(&MalVal, &MalVal) -> Result<bool, MalErr>) -> MalRet {
+    if a.len() == 1 {
+        num_pair(&a[0], &a[0])?;
+    }
+    for (x, y) in a.iter().tuple_windows() {
+        if !cmp(x, y)? {
+            return Ok(Bool(false));
+        }
+    }
+    Ok(Bool(true))
+}
+
+// Returns the argument itself, so (max 1 2.0) is 2.0 and (max 2 1.0) is 2
+fn min_max(a: &MalArgs, keep: fn(&MalVal, &MalVal) -> Result<bool, MalErr>) -> MalRet {
+    let mut best = a[0].clone();
+    num_pair(&best, &best)?;
+    for x in a[1..].iter() {
+        if !keep(&best, x)? {
+            best = x.clone();
+        }
+    }
+    Ok(best)
+}
+
+// Truncating division: (quot -7 2) is -3
+fn quot(a: MalArgs) -> MalRet {
+    if let Int(0) = a[1] {
+        return arithmetic_error("divide by zero");
+    }
+    Ok(match num_pair(&a[0], &a[1])? {
+        Ints(i, j) => match i.checked_div(j) {
+            Some(r) => Int(r),
+            None => big_int(BigInt::from(i) / BigInt::from(j)),
+        },
+        BigInts(i, j) => big_int(i / j),
+        Ratios(r, s) => big_int((r / s).trunc().to_integer()),
+        Floats(x, y) => Float((x / y).trunc()),
+    })
+}
+
+// Remainder of truncating division, with the sign of the dividend
+fn rem(a: MalArgs) -> MalRet {
+    if let Int(0) = a[1] {
+        return arithmetic_error("divide by zero");
+    }
+    Ok(match num_pair(&a[0], &a[1])? {
+        Ints(i, j) => Int(i.wrapping_rem(j)),
+        BigInts(i, j) => big_int(i % j),
+        Ratios(r, s) => ratio(r.clone() - s.clone() * (r / s).trunc()),
+        Floats(x, y) => Float(x % y),
+    })
+}
+
+// Remainder of flooring division, with the sign of the divisor
+fn modulo(a: MalArgs) -> MalRet {
+    let r = rem(a.clone())?;
+    if num_eq(&r, &Int(0))? || lt(&r, &Int(0))? == lt(&a[1], &Int(0))? {
+        Ok(r)
+    } else {
+        add(&r, &a[1])
+    }
+}
+
+fn abs(a: MalArgs) -> MalRet {
+    match a[0] {
+        Int(i) => Ok(match i.checked_abs() {
+            Some(r) => Int(r),
+            None => big_int(BigInt::from(i).abs()),
+        }),
+        MalVal::BigInt(ref b) => Ok(big_int(b.abs())),
+        Ratio(ref r) => Ok(ratio(r.abs())),
+        Float(f) => Ok(Float(f.abs())),
+        _ => Err(typed_error("type", "abs called with non-number")),
+    }
+}
+
+fn radix(a: &MalVal) -> Result<u32, MalErr> {
+    match *a {
+        Int(r) if (2..=36).contains(&r) => Ok(r as u32),
+        _ => Err(ErrString(
+            "radix must be an integer from 2 to 36".to_string(),
+        )),
+    }
+}
+
+// Digits from 0-9 then a-z, with a leading '-' when negative
+fn format_int(a: MalArgs) -> MalRet {
+    let radix = radix(&a[1])?;
+    match a[0] {
+        Int(i) => Ok(Str(BigInt::from(i).to_str_radix(radix))),
+        MalVal::BigInt(ref b) => Ok(Str(b.to_str_radix(radix))),
+        _ => Err(typed_error("type", "format-int called with non-integer")),
+    }
+}
+
+fn parse_int(a: MalArgs) -> MalRet {
+    let radix = radix(&a[1])?;
+    match a[0] {
+        Str(ref s) => int_from_str(s, radix)
+            .ok_or_else(|| ErrString(format!("invalid digits for radix {}: {}", radix, s))),
+        _ => error("parse-int called with non-string"),
+    }
+}
+
+// Integer division only stays integral when it is exact. Dividing by an
+// exact zero is an error, while floats follow IEEE 754 and give ##Inf/##NaN
+fn divide(a0: &MalVal, a1: &MalVal) -> MalRet {
+    Ok(match num_pair(a0, a1)? {
+        Ints(_, 0) => return arithmetic_error("divide by zero"),
+        BigInts(_, ref j) if j.is_zero() => return arithmetic_error("divide by zero"),
+        Ratios(_, ref s) if s.is_zero() => return arithmetic_error("divide by zero"),
+        Ints(i, j) if i.wrapping_rem(j) == 0 => match i.checked_div(j) {
+            Some(r) => Int(r),
+            None => big_int(BigInt::from(i) / BigInt::from(j)),
+        },
+        Ints(i, j) => ratio(BigRational::new(BigInt::from(i), BigInt::from(j))),
+        BigInts(i, j) => ratio(BigRational::new(i, j)),
+        Ratios(r, s) => ratio(r / s),
+        Floats(x, y) => Float(x / y),
+    })
+}
+
+fn numerator(a: MalArgs) -> MalRet {
+    match a[0] {
+        Ratio(ref r) => Ok(big_int(r.numer().clone())),
+        Int(_) | MalVal::BigInt(_) => Ok(a[0].clone()),
+        _ => Err(typed_error("type", "numerator called with non-rational")),
+    }
+}
+
+fn denominator(a: MalArgs) -> MalRet {
+    match a[0] {
+        Ratio(ref r) => Ok(big_int(r.denom().clone())),
+        Int(_) | MalVal::BigInt(_) => Ok(Int(1)),
+        _ => Err(typed_error("type", "denominator called with non-rational")),
+    }
+}
+
+// Uses the shortest decimal representation of the float, so that
+// (rationalize 0.1) is 1/10 rather than the exact binary fraction
+fn rationalize(a: MalArgs) -> MalRet {
+    match a[0] {
+        Float(f) if !f.is_finite() => {
+            arithmetic_error("rationalize: cannot rationalize non-finite float")
+        }
+        Float(f) => {
+            let sci = format!("{:e}", f);
+            let (mantissa, exp) = sci.split_at(sci.find('e').unwrap());
+            let exp: i64 = exp[1..].parse().unwrap();
+            let frac_len = mantissa.find('.').map_or(0, |p| mantissa.len() - p - 1);
+            let digits: BigInt = mantissa.replace('.', "").parse().unwrap();
+            let scale = exp - frac_len as i64;
+            let ten = BigInt::from(10);
+            let pow = num_traits::pow(ten, scale.unsigned_abs() as usize);
+            if scale >= 0 {
+                Ok(big_int(digits * pow))
+            } else {
+                Ok(ratio(BigRational::new(digits, pow)))
+            }
+        }
+        Int(_) | MalVal::BigInt(_) | Ratio(_) => Ok(a[0].clone()),
+        _ => Err(typed_error("type", "rationalize called with non-number")),
+    }
 }
 
 macro_rules! fn_is_type {
@@ -51,16 +327,10 @@ fn symbol(a: MalArgs) -> MalRet {
     }
 }
 
-fn readline(a: MalArgs) -> MalRet {
-    lazy_static! {
-        static ref RL: Mutex<Editor<()>> = Mutex::new(Editor::<()>::new());
-    }
-    //let mut rl = Editor::<()>::new();
-
+fn readline(rl: &RefCell<Editor<()>>, a: MalArgs) -> MalRet {
     match a[0] {
         Str(ref p) => {
-            //match rl.readline(p) {
-            match RL.lock().unwrap().readline(p) {
+            match rl.borrow_mut().readline(p) {
                 Ok(mut line) => {
                     // Remove any trailing \n or \r\n
                     if line.ends_with('\n') {
@@ -79,6 +349,69 @@ fn readline(a: MalArgs) -> MalRet {
     }
 }
 
+// The builtins from here to read_all_string look up dynamic variables in
+// the global environment, so the interpreter defines them rather than ns
+
+// *print-right-margin* from env
+pub fn right_margin(env: &Env) -> usize {
+    match env_get(env, &Sym("*print-right-margin*".to_string())) {
+        Ok(Int(n)) if n > 0 => n as usize,
+        _ => DEFAULT_RIGHT_MARGIN,
+    }
+}
+
+// *print-length* and *print-level* from env; nil leaves the printer
+// unlimited
+pub fn print_limits(env: &Env) -> PrintLimits {
+    let limit = |name: &str| match env_get(env, &Sym(name.to_string())) {
+        Ok(Int(n)) if n >= 0 => Some(n as usize),
+        _ => None,
+    };
+    PrintLimits {
+        length: limit("*print-length*"),
+        level: limit("*print-level*"),
+    }
+}
+
+// (pprint-str x) or (pprint-str x right-margin)
+pub fn pprint_str(a: MalArgs, env: &Env) -> MalRet {
+    let margin = match a.get(1) {
+        None => right_margin(env),
+        Some(Int(n)) if *n > 0 => *n as usize,
+        _ => return error("pprint-str: right margin must be a positive integer"),
+    };
+    Ok(Str(a[0].pr_pretty(margin, print_limits(env))?))
+}
+
+pub fn pprint(a: MalArgs, env: &Env) -> MalRet {
+    if let Str(s) = pprint_str(a, env)? {
+        println!("{}", s);
+    }
+    Ok(Nil)
+}
+
+// The reader builtins take *host-language* and *data-readers* from env
+pub fn read_string(a: MalArgs, env: &Env) -> MalRet {
+    match a[0] {
+        Str(ref s) => read_str_in(s, Some(env)),
+        _ => error("expecting (str) arg"),
+    }
+}
+
+// (read-all-string s) or (read-all-string s file): every form in s, with
+// file recorded in their positions
+pub fn read_all_string(a: MalArgs, env: &Env) -> MalRet {
+    let file = match a.get(1) {
+        None | Some(Nil) => None,
+        Some(Str(f)) => Some(&f[..]),
+        _ => return error("read-all-string: file name must be a string"),
+    };
+    match a[0] {
+        Str(ref s) => Ok(list!(read_all(s, file, Some(env))?)),
+        _ => error("expecting (str) arg"),
+    }
+}
+
 fn slurp(f: String) -> MalRet {
     let mut s = String::new();
     match File::open(f).and_then(|mut f| f.read_to_string(&mut s)) {
@@ -100,76 +433,83 @@ fn time_ms(_a: MalArgs) -> MalRet {
 fn get(a: MalArgs) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
         (Nil, _) => Ok(Nil),
-        (Hash(ref hm, _), Str(ref s)) => match hm.get(s) {
+        (Hash(ref hm, _), ref k) => match hm.get(k) {
             Some(mv) => Ok(mv.clone()),
             None => Ok(Nil),
         },
+        (Set(ref hs, _), ref k) if hs.contains(k) => Ok(k.clone()),
+        (Set(_, _), _) => Ok(Nil),
         _ => error("illegal get args"),
     }
 }
 
 fn assoc(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => _assoc((**hm).clone(), a[1..].to_vec()),
+        Hash(ref hm, ref meta) => _assoc(hm.clone(), meta.clone(), a[1..].to_vec()),
         _ => error("assoc on non-Hash Map"),
     }
 }
 
 fn dissoc(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => _dissoc((**hm).clone(), a[1..].to_vec()),
+        Hash(ref hm, ref meta) => _dissoc(hm.clone(), meta.clone(), a[1..].to_vec()),
         _ => error("dissoc on non-Hash Map"),
     }
 }
 
 fn contains_q(a: MalArgs) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
-        (Hash(ref hm, _), Str(ref s)) => Ok(Bool(hm.contains_key(s))),
+        (Hash(ref hm, _), ref k) => Ok(Bool(hm.contains_key(k))),
+        (Set(ref hs, _), ref k) => Ok(Bool(hs.contains(k))),
         _ => error("illegal get args"),
     }
 }
 
 fn keys(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.keys().map(|k| { Str(k.to_string()) }).collect())),
+        Hash(ref hm, _) => Ok(list!(hm.keys().cloned().collect::<MalSeq>())),
         _ => error("keys requires Hash Map"),
     }
 }
 
 fn vals(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.values().map(|v| { v.clone() }).collect())),
+        Hash(ref hm, _) => Ok(list!(hm.values().cloned().collect::<MalSeq>())),
         _ => error("keys requires Hash Map"),
     }
 }
 
 fn vec(a: MalArgs) -> MalRet {
     match a[0] {
-        List(ref v, _) | Vector(ref v, _) => Ok(vector!(v.to_vec())),
+        List(ref v, _) | Vector(ref v, _) => Ok(vector!(v.clone())),
+        LazySeq(_) => Ok(vector!(a[0].seq_vec()?)),
         _ => error("non-seq passed to vec"),
     }
 }
 
 fn cons(a: MalArgs) -> MalRet {
     match a[1].clone() {
-        List(v, _) | Vector(v, _) => {
-            let mut new_v = vec![a[0].clone()];
-            new_v.extend_from_slice(&v);
-            Ok(list!(new_v.to_vec()))
+        List(mut v, _) | Vector(mut v, _) => {
+            v.push_front(a[0].clone());
+            Ok(list!(v))
         }
+        LazySeq(_) => Ok(lazy_cons(a[0].clone(), a[1].clone())),
         _ => error("cons expects seq as second arg"),
     }
 }
 
 fn concat(a: MalArgs) -> MalRet {
-    let mut new_v = vec![];
+    if a.iter().any(is_lazy) {
+        return Ok(lazy_concat(list![], a));
+    }
+    let mut new_v = MalSeq::new();
     for seq in a.iter() {
         match seq {
-            List(v, _) | Vector(v, _) => new_v.extend_from_slice(v),
+            List(v, _) | Vector(v, _) => new_v.append(v.clone()),
             _ => return error("non-seq passed to concat"),
         }
     }
-    Ok(list!(new_v.to_vec()))
+    Ok(list!(new_v))
 }
 
 fn nth(a: MalArgs) -> MalRet {
@@ -180,30 +520,59 @@ fn nth(a: MalArgs) -> MalRet {
             }
             Ok(seq[idx as usize].clone())
         }
+        (LazySeq(_), Int(idx)) if idx >= 0 => {
+            let mut cur = a[0].clone();
+            for _ in 0..idx {
+                cur = match cur.seq_step()? {
+                    Some((_, rest)) => rest,
+                    None => return error("nth: index out of range"),
+                };
+            }
+            match cur.seq_step()? {
+                Some((first, _)) => Ok(first),
+                None => error("nth: index out of range"),
+            }
+        }
         _ => error("invalid args to nth"),
     }
 }
 
+fn subvec(a: MalArgs) -> MalRet {
+    let v = match a[0] {
+        Vector(ref v, _) => v,
+        _ => return error("subvec called with non-vector"),
+    };
+    let start = match a[1] {
+        Int(i) if i >= 0 => i as usize,
+        _ => return error("subvec: invalid start index"),
+    };
+    let end = match a.get(2) {
+        None => v.len(),
+        Some(Int(i)) if *i >= 0 => *i as usize,
+        _ => return error("subvec: invalid end index"),
+    };
+    if start > end || end > v.len() {
+        return error("subvec: index out of range");
+    }
+    Ok(vector!(v.skip(start).take(end - start)))
+}
+
 fn first(a: MalArgs) -> MalRet {
     match a[0].clone() {
-        List(ref seq, _) | Vector(ref seq, _) if seq.len() == 0 => Ok(Nil),
+        List(ref seq, _) | Vector(ref seq, _) if seq.is_empty() => Ok(Nil),
         List(ref seq, _) | Vector(ref seq, _) => Ok(seq[0].clone()),
         Nil => Ok(Nil),
+        LazySeq(_) => Ok(a[0].seq_step()?.map_or(Nil, |(first, _)| first)),
         _ => error("invalid args to first"),
     }
 }
 
 fn rest(a: MalArgs) -> MalRet {
     match a[0].clone() {
-        List(ref seq, _) | Vector(ref seq, _) => {
-            if seq.len() > 1 {
-                Ok(list!(seq[1..].to_vec()))
-            } else {
-                Ok(list![])
-            }
-        }
+        List(ref seq, _) | Vector(ref seq, _) => Ok(list!(seq_rest(seq))),
         Nil => Ok(list![]),
-        _ => error("invalid args to first"),
+        LazySeq(_) => Ok(a[0].seq_step()?.map_or(list![], |(_, rest)| rest)),
+        _ => error("invalid args to rest"),
     }
 }
 
@@ -212,13 +581,23 @@ fn apply(a: MalArgs) -> MalRet {
         List(ref v, _) | Vector(ref v, _) => {
             let f = &a[0];
             let mut fargs = a[1..a.len() - 1].to_vec();
-            fargs.extend_from_slice(&v);
+            fargs.extend(v.iter().cloned());
             f.apply(fargs)
         }
+        LazySeq(_) => {
+            let mut fargs = a[1..a.len() - 1].to_vec();
+            fargs.extend(a[a.len() - 1].seq_vec()?);
+            a[0].apply(fargs)
+        }
         _ => error("apply called with non-seq"),
     }
 }
 
+// map, filter, concat, take and drop are lazy only over lazy seqs. Over
+// lists and vectors they are eager and return lists, because the shared
+// step9 tests need (try* (map throw (list "my err")) ...) to catch the
+// error, which a lazy map would only raise once printed outside the try*.
+// (lazy-seq coll) turns a list or vector into a lazy seq first.
 fn map(a: MalArgs) -> MalRet {
     match a[1] {
         List(ref v, _) | Vector(ref v, _) => {
@@ -228,124 +607,724 @@ fn map(a: MalArgs) -> MalRet {
             }
             Ok(list!(res))
         }
+        Nil => Ok(list![]),
+        LazySeq(_) => Ok(lazy_map(a[0].clone(), a[1].clone())),
         _ => error("map called with non-seq"),
     }
 }
 
+fn is_lazy(a: &MalVal) -> bool {
+    matches!(a, LazySeq(_))
+}
+
+fn truthy(a: &MalVal) -> bool {
+    !matches!(a, Nil | Bool(false))
+}
+
+fn lazy_map(f: MalVal, coll: MalVal) -> MalVal {
+    lazy_native(move || match coll.seq_step()? {
+        Some((x, rest)) => Ok(lazy_cons(f.apply(vec![x])?, lazy_map(f, rest))),
+        None => Ok(list![]),
+    })
+}
+
+fn filter(a: MalArgs) -> MalRet {
+    match a[1] {
+        List(ref v, _) | Vector(ref v, _) => {
+            let mut res = vec![];
+            for mv in v.iter() {
+                if truthy(&a[0].apply(vec![mv.clone()])?) {
+                    res.push(mv.clone());
+                }
+            }
+            Ok(list!(res))
+        }
+        Nil => Ok(list![]),
+        LazySeq(_) => Ok(lazy_filter(a[0].clone(), a[1].clone())),
+        _ => error("filter called with non-seq"),
+    }
+}
+
+fn lazy_filter(f: MalVal, coll: MalVal) -> MalVal {
+    lazy_native(move || {
+        let mut cur = coll;
+        while let Some((x, rest)) = cur.seq_step()? {
+            if truthy(&f.apply(vec![x.clone()])?) {
+                return Ok(lazy_cons(x, lazy_filter(f, rest)));
+            }
+            cur = rest;
+        }
+        Ok(list![])
+    })
+}
+
+// cur is drained first, then each of more in turn. The last seq is
+// returned as it is rather than wrapped, so that a seq built by
+// concatenating onto itself, as (lazy-seq (concat [x] (f ...))) does,
+// is realized in a loop instead of one nested call per segment.
+fn lazy_concat(cur: MalVal, more: MalArgs) -> MalVal {
+    lazy_native(move || {
+        let mut cur = cur;
+        let mut more = more.into_iter();
+        loop {
+            if more.len() == 0 {
+                return Ok(cur);
+            }
+            match cur.seq_step()? {
+                Some((x, rest)) => return Ok(lazy_cons(x, lazy_concat(rest, more.collect()))),
+                None => cur = more.next().unwrap_or(Nil),
+            }
+        }
+    })
+}
+
+fn take(a: MalArgs) -> MalRet {
+    let n = match a[0] {
+        Int(n) => n.max(0) as usize,
+        _ => return error("take: count must be an integer"),
+    };
+    match a[1] {
+        List(ref v, _) | Vector(ref v, _) => Ok(list!(v.take(n.min(v.len())))),
+        Nil => Ok(list![]),
+        LazySeq(_) => Ok(lazy_take(n, a[1].clone())),
+        _ => error("take called with non-seq"),
+    }
+}
+
+fn lazy_take(n: usize, coll: MalVal) -> MalVal {
+    lazy_native(move || {
+        if n == 0 {
+            return Ok(list![]);
+        }
+        match coll.seq_step()? {
+            Some((x, rest)) => Ok(lazy_cons(x, lazy_take(n - 1, rest))),
+            None => Ok(list![]),
+        }
+    })
+}
+
+fn drop(a: MalArgs) -> MalRet {
+    let n = match a[0] {
+        Int(n) => n.max(0) as usize,
+        _ => return error("drop: count must be an integer"),
+    };
+    match a[1] {
+        List(ref v, _) | Vector(ref v, _) => Ok(list!(v.skip(n.min(v.len())))),
+        Nil => Ok(list![]),
+        LazySeq(_) => {
+            let coll = a[1].clone();
+            Ok(lazy_native(move || {
+                let mut cur = coll;
+                for _ in 0..n {
+                    match cur.seq_step()? {
+                        Some((_, rest)) => cur = rest,
+                        None => return Ok(list![]),
+                    }
+                }
+                Ok(cur)
+            }))
+        }
+        _ => error("drop called with non-seq"),
+    }
+}
+
+fn range(a: MalArgs) -> MalRet {
+    let ints = a
+        .iter()
+        .map(|v| match v {
+            Int(i) => Ok(*i),
+            _ => Err(ErrString("range: arguments must be integers".to_string())),
+        })
+        .collect::<Result<Vec<i64>, MalErr>>()?;
+    match ints[..] {
+        [] => Ok(lazy_range(0, None, 1)),
+        [end] => Ok(lazy_range(0, Some(end), 1)),
+        [start, end] => Ok(lazy_range(start, Some(end), 1)),
+        [start, end, step] => Ok(lazy_range(start, Some(end), step)),
+        _ => error("range: too many arguments"),
+    }
+}
+
+fn lazy_range(start: i64, end: Option<i64>, step: i64) -> MalVal {
+    lazy_native(move || {
+        let done = match end {
+            Some(end) => (step > 0 && start >= end) || (step < 0 && start <= end),
+            None => false,
+        };
+        if done {
+            return Ok(list![]);
+        }
+        let rest = match start.checked_add(step) {
+            Some(next) => lazy_range(next, end, step),
+            None => list![],
+        };
+        Ok(lazy_cons(Int(start), rest))
+    })
+}
+
+fn iterate(f: MalVal, x: MalVal) -> MalVal {
+    let next = x.clone();
+    lazy_cons(
+        x,
+        lazy_native(move || {
+            let v = f.apply(vec![next])?;
+            Ok(iterate(f, v))
+        }),
+    )
+}
+
+fn repeat(a: MalArgs) -> MalRet {
+    match a[..] {
+        [ref x] => Ok(repeat_forever(x.clone())),
+        [Int(n), ref x] => Ok(lazy_take(n.max(0) as usize, repeat_forever(x.clone()))),
+        _ => error("repeat: invalid arguments"),
+    }
+}
+
+fn repeat_forever(x: MalVal) -> MalVal {
+    lazy_native(move || Ok(lazy_cons(x.clone(), repeat_forever(x))))
+}
+
+// coll is realized once, when the cycle is first looked at, and then
+// indexed into round and round
+fn cycle(coll: MalVal) -> MalVal {
+    lazy_native(move || {
+        let items = coll.seq_vec()?;
+        if items.is_empty() {
+            return Ok(list![]);
+        }
+        Ok(cycle_from(Rc::new(items), 0))
+    })
+}
+
+fn cycle_from(items: Rc<MalArgs>, i: usize) -> MalVal {
+    lazy_native(move || {
+        let next = (i + 1) % items.len();
+        Ok(lazy_cons(items[i].clone(), cycle_from(items, next)))
+    })
+}
+
+fn realized_q(a: MalArgs) -> MalRet {
+    match a[0] {
+        LazySeq(ref cell) => Ok(Bool(matches!(
+            *cell.state.borrow(),
+            LazyState::Realized(_) | LazyState::Failed(_)
+        ))),
+        _ => error("realized? called on non-lazy value"),
+    }
+}
+
 fn conj(a: MalArgs) -> MalRet {
     match a[0] {
         List(ref v, _) => {
-            let sl = a[1..]
-                .iter()
-                .rev()
-                .map(|a| a.clone())
-                .collect::<Vec<MalVal>>();
-            Ok(list!([&sl[..], v].concat()))
+            let mut new_v = v.clone();
+            for e in a[1..].iter() {
+                new_v.push_front(e.clone());
+            }
+            Ok(list!(new_v))
+        }
+        Vector(ref v, _) => {
+            let mut new_v = v.clone();
+            new_v.extend(a[1..].iter().cloned());
+            Ok(vector!(new_v))
+        }
+        Hash(ref hm, ref meta) => {
+            let mut new_hm = hm.clone();
+            for e in a[1..].iter() {
+                match e {
+                    Vector(ref kv, _) if kv.len() == 2 => {
+                        new_hm.insert(kv[0].clone(), kv[1].clone());
+                    }
+                    Hash(ref other, _) => {
+                        new_hm.extend(other.iter().map(|(k, v)| (k.clone(), v.clone())))
+                    }
+                    _ => return error("conj: map entries must be [key value] vectors"),
+                }
+            }
+            Ok(Hash(new_hm, meta.clone()))
         }
-        Vector(ref v, _) => Ok(vector!([v, &a[1..]].concat())),
+        Set(ref hs, ref meta) => {
+            let mut new_hs = hs.clone();
+            new_hs.extend(a[1..].iter().cloned());
+            Ok(Set(new_hs, meta.clone()))
+        }
+        LazySeq(_) => Ok(a[1..]
+            .iter()
+            .fold(a[0].clone(), |acc, e| lazy_cons(e.clone(), acc))),
         _ => error("conj: called with non-seq"),
     }
 }
 
-fn seq(a: MalArgs) -> MalRet {
+fn set(a: MalArgs) -> MalRet {
+    match a[0] {
+        List(ref v, _) | Vector(ref v, _) => Ok(Set(v.iter().cloned().collect(), Rc::new(Nil))),
+        Set(ref hs, _) => Ok(Set(hs.clone(), Rc::new(Nil))),
+        Nil => hash_set(vec![]),
+        LazySeq(_) => hash_set(a[0].seq_vec()?),
+        _ => error("set: called with non-seq"),
+    }
+}
+
+fn disj(a: MalArgs) -> MalRet {
+    match a[0] {
+        Set(ref hs, ref meta) => {
+            let mut new_hs = hs.clone();
+            for k in a[1..].iter() {
+                new_hs.remove(k);
+            }
+            Ok(Set(new_hs, meta.clone()))
+        }
+        Nil => Ok(Nil),
+        _ => error("disj: called with non-set"),
+    }
+}
+
+fn to_sets(name: &str, a: &MalArgs) -> Result<Vec<MalSet>, MalErr> {
+    a.iter()
+        .map(|s| match s {
+            Set(ref hs, _) => Ok(hs.clone()),
+            _ => Err(ErrString(format!("{}: called with non-set", name))),
+        })
+        .collect()
+}
+
+fn union(a: MalArgs) -> MalRet {
+    let sets = to_sets("union", &a)?;
+    Ok(Set(MalSet::unions(sets), Rc::new(Nil)))
+}
+
+fn intersection(a: MalArgs) -> MalRet {
+    let mut sets = to_sets("intersection", &a)?.into_iter();
+    let first = sets.next().unwrap_or_default();
+    Ok(Set(
+        sets.fold(first, |acc, s| acc.intersection(s)),
+        Rc::new(Nil),
+    ))
+}
+
+fn difference(a: MalArgs) -> MalRet {
+    let mut sets = to_sets("difference", &a)?.into_iter();
+    let first = sets.next().unwrap_or_default();
+    Ok(Set(
+        sets.fold(first, |acc, s| acc.relative_complement(s)),
+        Rc::new(Nil),
+    ))
+}
+
+fn subset_q(a: MalArgs) -> MalRet {
+    match (&a[0], &a[1]) {
+        (Set(ref s1, _), Set(ref s2, _)) => Ok(Bool(s1.is_subset(s2))),
+        _ => error("subset?: called with non-set"),
+    }
+}
+
+fn keyword(a: MalArgs) -> MalRet {
+    match (a.first(), a.get(1)) {
+        (Some(Nil), Some(Str(n))) => Ok(crate::types::keyword(n)),
+        (Some(Str(ns)), Some(Str(n))) => Ok(crate::types::keyword(&format!("{}/{}", ns, n))),
+        (Some(k), None) => k.keyword(),
+        _ => error("invalid args to keyword"),
+    }
+}
+
+// Splits "ns/name" into its namespace and name parts; a lone "/" is a name
+fn split_ns(s: &str) -> (Option<&str>, &str) {
+    match s.find('/') {
+        Some(i) if s.len() > 1 => (Some(&s[..i]), &s[i + 1..]),
+        _ => (None, s),
+    }
+}
+
+fn name(a: MalArgs) -> MalRet {
+    match a[0] {
+        Keyword(ref k) => Ok(Str(split_ns(k).1.to_string())),
+        Sym(ref s) => Ok(Str(split_ns(s).1.to_string())),
+        Str(ref s) => Ok(Str(s.clone())),
+        _ => error("name called with non-named value"),
+    }
+}
+
+fn namespace(a: MalArgs) -> MalRet {
+    let ns = match a[0] {
+        Keyword(ref k) => split_ns(k).0,
+        Sym(ref s) => split_ns(s).0,
+        _ => return error("namespace called with non-named value"),
+    };
+    Ok(ns.map_or(Nil, |ns| Str(ns.to_string())))
+}
+
+fn int_to_char(a: MalArgs) -> MalRet {
+    match a[0] {
+        Int(i) if (0..=u32::MAX as i64).contains(&i) => match std::char::from_u32(i as u32) {
+            Some(c) => Ok(Char(c)),
+            None => error(&format!("int->char: invalid code point {}", i)),
+        },
+        Char(_) => Ok(a[0].clone()),
+        _ => error("int->char: called with non-int"),
+    }
+}
+
+fn char_to_int(a: MalArgs) -> MalRet {
+    match a[0] {
+        Char(c) => Ok(Int(c as i64)),
+        _ => error("char->int: called with non-char"),
+    }
+}
+
+// Case mapping can change the length ('ß' upper-cases to "SS"), in which
+// case a char comes back as a string
+fn change_case(a: MalArgs, f: fn(&str) -> String) -> MalRet {
     match a[0] {
-        List(ref v, _) | Vector(ref v, _) if v.len() == 0 => Ok(Nil),
-        List(ref v, _) | Vector(ref v, _) => Ok(list!(v.to_vec())),
-        Str(ref s) if s.len() == 0 => Ok(Nil),
-        Str(ref s) if !a[0].keyword_q() => {
-            Ok(list!(s.chars().map(|c| { Str(c.to_string()) }).collect()))
+        Str(ref s) => Ok(Str(f(s))),
+        Char(c) => {
+            let s = f(&c.to_string());
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Ok(Char(c)),
+                _ => Ok(Str(s)),
+            }
         }
+        _ => error("expecting (str) or (char) arg"),
+    }
+}
+
+fn inst_to_ms(a: MalArgs) -> MalRet {
+    match a[0] {
+        Tagged(ref t, ref v) if &**t == "inst" => match **v {
+            Str(ref s) => Ok(Int(inst_ms(s)?)),
+            _ => error("inst-ms: malformed inst"),
+        },
+        _ => error("inst-ms: expected an inst"),
+    }
+}
+
+fn re_pattern(a: MalArgs) -> MalRet {
+    match a[0] {
+        Str(ref s) => regex(s),
+        MalVal::Regex(_) => Ok(a[0].clone()),
+        _ => error("re-pattern: expected a string"),
+    }
+}
+
+// The matched text, or when the regex has groups a vector of the match
+// followed by each group, nil for groups that took no part
+fn re_groups(caps: &Captures) -> MalVal {
+    if caps.len() == 1 {
+        return Str(caps[0].to_string());
+    }
+    vector!(caps
+        .iter()
+        .map(|m| m.map_or(Nil, |m| Str(m.as_str().to_string())))
+        .collect::<MalSeq>())
+}
+
+fn re_args(a: &MalArgs) -> Result<(&Regex, &str), MalErr> {
+    match (&a[0], &a[1]) {
+        (MalVal::Regex(re), Str(s)) => Ok((re, s)),
+        _ => Err(ErrString("expecting (regex,string) args".to_string())),
+    }
+}
+
+fn re_find(a: MalArgs) -> MalRet {
+    let (re, s) = re_args(&a)?;
+    Ok(re.captures(s).map_or(Nil, |caps| re_groups(&caps)))
+}
+
+// Only a match of the whole string counts
+fn re_matches(a: MalArgs) -> MalRet {
+    let (re, s) = re_args(&a)?;
+    let whole = Regex::new(&format!("^(?:{})$", re.as_str())).unwrap();
+    Ok(whole.captures(s).map_or(Nil, |caps| re_groups(&caps)))
+}
+
+fn re_seq(a: MalArgs) -> MalRet {
+    let (re, s) = re_args(&a)?;
+    Ok(list!(re
+        .captures_iter(s)
+        .map(|caps| re_groups(&caps))
+        .collect::<MalSeq>()))
+}
+
+// Errors raised realizing lazy seqs are thrown, so that they can be
+// caught instead of ending up in the output. *print-length* and
+// *print-level* cut short str and println as well as pr-str and prn, as
+// they do println in Clojure, so an infinite seq cannot flood the output.
+pub fn pr_args(a: &MalArgs, print_readably: bool, join: &str, env: &Env) -> Result<String, MalErr> {
+    let limits = print_limits(env);
+    let strs = a
+        .iter()
+        .map(|v| v.pr_limited(print_readably, limits))
+        .collect::<Result<Vec<String>, MalErr>>()?;
+    Ok(strs.join(join))
+}
+
+// A string seqs as one-character strings, not chars. The shared stepA
+// tests, which every mal implementation passes, expect (seq "abc") to be
+// ("a" "b" "c").
+fn seq(a: MalArgs) -> MalRet {
+    match a[0] {
+        List(ref v, _) | Vector(ref v, _) if v.is_empty() => Ok(Nil),
+        List(ref v, _) | Vector(ref v, _) => Ok(list!(v.clone())),
+        Set(ref hs, _) if hs.is_empty() => Ok(Nil),
+        Set(ref hs, _) => Ok(list!(hs.iter().cloned().collect::<MalSeq>())),
+        Str(ref s) if s.is_empty() => Ok(Nil),
+        Str(ref s) => Ok(list!(s
+            .chars()
+            .map(|c| { Str(c.to_string()) })
+            .collect::<MalSeq>())),
         Nil => Ok(Nil),
+        LazySeq(_) if a[0].seq_step()?.is_none() => Ok(Nil),
+        LazySeq(_) => Ok(a[0].clone()),
         _ => error("seq: called with non-seq"),
     }
 }
 
+// Builtins are named after the symbol they are bound to, and their
+// arguments are counted against arity before they are called
+fn named(name: &'static str, arity: Arity, f: MalVal) -> MalVal {
+    match f {
+        Func(nf, meta) => Func(
+            Rc::new(NativeFn {
+                name: Rc::from(name),
+                arity,
+                f: nf.f.clone(),
+            }),
+            meta,
+        ),
+        _ => f,
+    }
+}
+
 pub fn ns() -> Vec<(&'static str, MalVal)> {
+    let rl = RefCell::new(Editor::<()>::new());
     vec![
-        ("=", func(|a| Ok(Bool(a[0] == a[1])))),
-        ("throw", func(|a| Err(ErrMalVal(a[0].clone())))),
-        ("nil?", func(fn_is_type!(Nil))),
-        ("true?", func(fn_is_type!(Bool(true)))),
-        ("false?", func(fn_is_type!(Bool(false)))),
-        ("symbol", func(symbol)),
-        ("symbol?", func(fn_is_type!(Sym(_)))),
         (
-            "string?",
-            func(fn_is_type!(Str(ref s) if !s.starts_with("\u{29e}"))),
+            "=",
+            Arity::at_least(1),
+            func(|a| Ok(Bool(a.iter().tuple_windows().all(|(x, y)| x == y)))),
+        ),
+        (
+            "not=",
+            Arity::at_least(1),
+            func(|a| Ok(Bool(!a.iter().tuple_windows().all(|(x, y)| x == y)))),
         ),
-        ("keyword", func(|a| a[0].keyword())),
+        (
+            "throw",
+            Arity::exactly(1),
+            func(|a| Err(ErrMalVal(a[0].clone()))),
+        ),
+        ("nil?", Arity::exactly(1), func(fn_is_type!(Nil))),
+        ("true?", Arity::exactly(1), func(fn_is_type!(Bool(true)))),
+        ("false?", Arity::exactly(1), func(fn_is_type!(Bool(false)))),
+        ("symbol", Arity::exactly(1), func(symbol)),
+        ("symbol?", Arity::exactly(1), func(fn_is_type!(Sym(_)))),
+        ("char", Arity::exactly(1), func(int_to_char)),
+        ("char?", Arity::exactly(1), func(fn_is_type!(Char(_)))),
+        ("int->char", Arity::exactly(1), func(int_to_char)),
+        ("char->int", Arity::exactly(1), func(char_to_int)),
+        (
+            "upper-case",
+            Arity::exactly(1),
+            func(|a| change_case(a, str::to_uppercase)),
+        ),
+        (
+            "lower-case",
+            Arity::exactly(1),
+            func(|a| change_case(a, str::to_lowercase)),
+        ),
+        ("name", Arity::exactly(1), func(name)),
+        ("namespace", Arity::exactly(1), func(namespace)),
+        ("string?", Arity::exactly(1), func(fn_is_type!(Str(_)))),
+        ("re-pattern", Arity::exactly(1), func(re_pattern)),
+        ("re-find", Arity::exactly(2), func(re_find)),
+        ("re-matches", Arity::exactly(2), func(re_matches)),
+        ("re-seq", Arity::exactly(2), func(re_seq)),
+        ("inst-ms", Arity::exactly(1), func(inst_to_ms)),
+        (
+            "inst?",
+            Arity::exactly(1),
+            func(fn_is_type!(Tagged(ref t, _) if &**t == "inst")),
+        ),
+        (
+            "uuid?",
+            Arity::exactly(1),
+            func(fn_is_type!(Tagged(ref t, _) if &**t == "uuid")),
+        ),
+        (
+            "regex?",
+            Arity::exactly(1),
+            func(fn_is_type!(MalVal::Regex(_))),
+        ),
+        ("keyword", Arity::between(1, 2), func(keyword)),
         (
             "keyword?",
-            func(fn_is_type!(Str(ref s) if s.starts_with("\u{29e}"))),
+            Arity::exactly(1),
+            func(|a| Ok(Bool(a[0].keyword_q()))),
+        ),
+        (
+            "number?",
+            Arity::exactly(1),
+            func(fn_is_type!(Int(_), MalVal::BigInt(_), Ratio(_), Float(_))),
+        ),
+        ("ratio?", Arity::exactly(1), func(fn_is_type!(Ratio(_)))),
+        (
+            "int?",
+            Arity::exactly(1),
+            func(fn_is_type!(Int(_), MalVal::BigInt(_))),
         ),
-        ("number?", func(fn_is_type!(Int(_)))),
+        ("float?", Arity::exactly(1), func(fn_is_type!(Float(_)))),
         (
             "fn?",
-            func(fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_))),
+            Arity::exactly(1),
+            func(fn_is_type!(MalFunc(ref c, _) if !c.is_macro,Func(_,_))),
         ),
         (
             "macro?",
-            func(fn_is_type!(MalFunc{is_macro,..} if is_macro)),
+            Arity::exactly(1),
+            func(fn_is_type!(MalFunc(ref c, _) if c.is_macro)),
         ),
-        ("pr-str", func(|a| Ok(Str(pr_seq(&a, true, "", "", " "))))),
-        ("str", func(|a| Ok(Str(pr_seq(&a, false, "", "", ""))))),
         (
-            "prn",
+            "readline",
+            Arity::exactly(1),
+            func(move |a| readline(&rl, a)),
+        ),
+        ("slurp", Arity::exactly(1), func(fn_str!(|f| { slurp(f) }))),
+        ("<", Arity::at_least(1), func(|a| chain_cmp(&a, lt))),
+        ("<=", Arity::at_least(1), func(|a| chain_cmp(&a, le))),
+        (">", Arity::at_least(1), func(|a| chain_cmp(&a, gt))),
+        (">=", Arity::at_least(1), func(|a| chain_cmp(&a, ge))),
+        ("==", Arity::at_least(1), func(|a| chain_cmp(&a, num_eq))),
+        ("+", Arity::at_least(0), func(|a| fold_num(&a, Int(0), add))),
+        (
+            "-",
+            Arity::at_least(1),
+            func(|a| fold_num_inverse(&a, Int(0), sub)),
+        ),
+        ("*", Arity::at_least(0), func(|a| fold_num(&a, Int(1), mul))),
+        (
+            "/",
+            Arity::at_least(1),
+            func(|a| fold_num_inverse(&a, Int(1), divide)),
+        ),
+        ("min", Arity::at_least(1), func(|a| min_max(&a, le))),
+        ("max", Arity::at_least(1), func(|a| min_max(&a, ge))),
+        ("quot", Arity::exactly(2), func(quot)),
+        ("rem", Arity::exactly(2), func(rem)),
+        ("mod", Arity::exactly(2), func(modulo)),
+        ("inc", Arity::exactly(1), func(|a| add(&a[0], &Int(1)))),
+        ("dec", Arity::exactly(1), func(|a| sub(&a[0], &Int(1)))),
+        ("abs", Arity::exactly(1), func(abs)),
+        ("format-int", Arity::exactly(2), func(format_int)),
+        ("parse-int", Arity::exactly(2), func(parse_int)),
+        ("numerator", Arity::exactly(1), func(numerator)),
+        ("denominator", Arity::exactly(1), func(denominator)),
+        ("rationalize", Arity::exactly(1), func(rationalize)),
+        ("time-ms", Arity::exactly(0), func(time_ms)),
+        (
+            "sequential?",
+            Arity::exactly(1),
+            func(|a| Ok(Bool(a[0].sequential_q()))),
+        ),
+        (
+            "seq?",
+            Arity::exactly(1),
+            func(fn_is_type!(List(_, _), LazySeq(_))),
+        ),
+        ("list", Arity::at_least(0), func(|a| Ok(list!(a)))),
+        ("list?", Arity::exactly(1), func(fn_is_type!(List(_, _)))),
+        ("vector", Arity::at_least(0), func(|a| Ok(vector!(a)))),
+        (
+            "vector?",
+            Arity::exactly(1),
+            func(fn_is_type!(Vector(_, _))),
+        ),
+        ("hash-map", Arity::at_least(0), func(hash_map)),
+        ("map?", Arity::exactly(1), func(fn_is_type!(Hash(_, _)))),
+        ("hash-set", Arity::at_least(0), func(hash_set)),
+        ("set", Arity::exactly(1), func(set)),
+        ("set?", Arity::exactly(1), func(fn_is_type!(Set(_, _)))),
+        ("disj", Arity::at_least(1), func(disj)),
+        ("union", Arity::at_least(0), func(union)),
+        ("intersection", Arity::at_least(0), func(intersection)),
+        ("difference", Arity::at_least(1), func(difference)),
+        ("subset?", Arity::exactly(2), func(subset_q)),
+        ("assoc", Arity::at_least(1), func(assoc)),
+        ("dissoc", Arity::at_least(1), func(dissoc)),
+        ("get", Arity::exactly(2), func(get)),
+        ("contains?", Arity::exactly(2), func(contains_q)),
+        ("keys", Arity::exactly(1), func(keys)),
+        ("vals", Arity::exactly(1), func(vals)),
+        ("vec", Arity::exactly(1), func(vec)),
+        ("cons", Arity::exactly(2), func(cons)),
+        ("concat", Arity::at_least(0), func(concat)),
+        ("empty?", Arity::exactly(1), func(|a| a[0].empty_q())),
+        ("nth", Arity::exactly(2), func(nth)),
+        ("subvec", Arity::between(2, 3), func(subvec)),
+        ("first", Arity::exactly(1), func(first)),
+        ("rest", Arity::exactly(1), func(rest)),
+        ("count", Arity::exactly(1), func(|a| a[0].count())),
+        ("apply", Arity::at_least(2), func(apply)),
+        ("map", Arity::exactly(2), func(map)),
+        ("filter", Arity::exactly(2), func(filter)),
+        ("take", Arity::exactly(2), func(take)),
+        ("drop", Arity::exactly(2), func(drop)),
+        ("range", Arity::between(0, 3), func(range)),
+        (
+            "iterate",
+            Arity::exactly(2),
+            func(|a| Ok(iterate(a[0].clone(), a[1].clone()))),
+        ),
+        ("repeat", Arity::between(1, 2), func(repeat)),
+        (
+            "cycle",
+            Arity::exactly(1),
+            func(|a| Ok(cycle(a[0].clone()))),
+        ),
+        (
+            "lazy-seq*",
+            Arity::exactly(1),
+            func(|a| Ok(lazy_seq(a[0].clone()))),
+        ),
+        ("realized?", Arity::exactly(1), func(realized_q)),
+        (
+            "doall",
+            Arity::exactly(1),
             func(|a| {
-                println!("{}", pr_seq(&a, true, "", "", " "));
-                Ok(Nil)
+                a[0].seq_vec()?;
+                Ok(a[0].clone())
             }),
         ),
         (
-            "println",
+            "dorun",
+            Arity::exactly(1),
             func(|a| {
-                println!("{}", pr_seq(&a, false, "", "", " "));
+                a[0].seq_vec()?;
                 Ok(Nil)
             }),
         ),
-        ("read-string", func(fn_str!(|s| { read_str(s) }))),
-        ("readline", func(readline)),
-        ("slurp", func(fn_str!(|f| { slurp(f) }))),
-        ("<", func(fn_t_int_int!(Bool, |i, j| { i < j }))),
-        ("<=", func(fn_t_int_int!(Bool, |i, j| { i <= j }))),
-        (">", func(fn_t_int_int!(Bool, |i, j| { i > j }))),
-        (">=", func(fn_t_int_int!(Bool, |i, j| { i >= j }))),
-        ("+", func(fn_t_int_int!(Int, |i, j| { i + j }))),
-        ("-", func(fn_t_int_int!(Int, |i, j| { i - j }))),
-        ("*", func(fn_t_int_int!(Int, |i, j| { i * j }))),
-        ("/", func(fn_t_int_int!(Int, |i, j| { i / j }))),
-        ("time-ms", func(time_ms)),
-        ("sequential?", func(fn_is_type!(List(_, _), Vector(_, _)))),
-        ("list", func(|a| Ok(list!(a)))),
-        ("list?", func(fn_is_type!(List(_, _)))),
-        ("vector", func(|a| Ok(vector!(a)))),
-        ("vector?", func(fn_is_type!(Vector(_, _)))),
-        ("hash-map", func(|a| hash_map(a))),
-        ("map?", func(fn_is_type!(Hash(_, _)))),
-        ("assoc", func(assoc)),
-        ("dissoc", func(dissoc)),
-        ("get", func(get)),
-        ("contains?", func(contains_q)),
-        ("keys", func(keys)),
-        ("vals", func(vals)),
-        ("vec", func(vec)),
-        ("cons", func(cons)),
-        ("concat", func(concat)),
-        ("empty?", func(|a| a[0].empty_q())),
-        ("nth", func(nth)),
-        ("first", func(first)),
-        ("rest", func(rest)),
-        ("count", func(|a| a[0].count())),
-        ("apply", func(apply)),
-        ("map", func(map)),
-        ("conj", func(conj)),
-        ("seq", func(seq)),
-        ("meta", func(|a| a[0].get_meta())),
-        ("with-meta", func(|a| a[0].clone().with_meta(&a[1]))),
-        ("atom", func(|a| Ok(atom(&a[0])))),
-        ("atom?", func(fn_is_type!(Atom(_)))),
-        ("deref", func(|a| a[0].deref())),
-        ("reset!", func(|a| a[0].reset_bang(&a[1]))),
-        ("swap!", func(|a| a[0].swap_bang(&a[1..].to_vec()))),
+        ("conj", Arity::at_least(1), func(conj)),
+        ("seq", Arity::exactly(1), func(seq)),
+        ("meta", Arity::exactly(1), func(|a| a[0].get_meta())),
+        (
+            "with-meta",
+            Arity::exactly(2),
+            func(|a| a[0].clone().with_meta(&a[1])),
+        ),
+        ("atom", Arity::exactly(1), func(|a| Ok(atom(&a[0])))),
+        ("atom?", Arity::exactly(1), func(fn_is_type!(Atom(_)))),
+        ("deref", Arity::exactly(1), func(|a| a[0].deref())),
+        (
+            "reset!",
+            Arity::exactly(2),
+            func(|a| a[0].reset_bang(&a[1])),
+        ),
+        (
+            "swap!",
+            Arity::at_least(2),
+            func(|a| a[0].swap_bang(&a[1..].to_vec())),
+        ),
     ]
+    .into_iter()
+    .map(|(name, arity, f)| (name, named(name, arity, f)))
+    .collect()
 }
@@ -5,7 +5,7 @@ use fnv::FnvHashMap;
 
 use crate::types::MalErr::ErrString;
 use crate::types::MalVal::{List, Nil, Sym, Vector};
-use crate::types::{error, MalErr, MalRet, MalVal};
+use crate::types::{arity_error, error, Arity, MalErr, MalRet, MalSeq, MalVal};
 
 #[derive(Debug)]
 pub struct EnvStruct {
@@ -21,7 +21,7 @@ pub type Env = Rc<EnvStruct>;
 pub fn env_new(outer: Option<Env>) -> Env {
     Rc::new(EnvStruct {
         data: RefCell::new(FnvHashMap::default()),
-        outer: outer,
+        outer,
     })
 }
 
@@ -30,10 +30,22 @@ pub fn env_bind(outer: Option<Env>, mbinds: MalVal, exprs: Vec<MalVal>) -> Resul
     let env = env_new(outer);
     match mbinds {
         List(binds, _) | Vector(binds, _) => {
+            let arity = match binds.iter().position(|b| matches!(b, Sym(s) if s == "&")) {
+                Some(i) => Arity::at_least(i),
+                None => Arity::exactly(binds.len()),
+            };
+            if !arity.accepts(exprs.len()) {
+                return Err(arity_error("fn", exprs.len(), arity));
+            }
             for (i, b) in binds.iter().enumerate() {
                 match b {
                     Sym(s) if s == "&" => {
-                        env_set(&env, binds[i + 1].clone(), list!(exprs[i..].to_vec()))?;
+                        let rest = binds.get(i + 1).cloned().unwrap_or(Nil);
+                        env_set(
+                            &env,
+                            rest,
+                            list!(exprs[i..].iter().cloned().collect::<MalSeq>()),
+                        )?;
                         break;
                     }
                     _ => {
@@ -0,0 +1,564 @@
+use std::rc::Rc;
+
+use itertools::Itertools;
+
+use crate::env::{env_bind, env_find, env_get, env_new, env_set, env_sets, Env};
+use crate::reader::{self, StreamReader};
+use crate::types::MalErr::{ErrAt, ErrString};
+use crate::types::MalVal::{
+    Bool, Func, Hash, Keyword, LazySeq, List, MalFunc, Nil, Set, Str, Sym, Vector,
+};
+use crate::types::{
+    arity_error, error, native_fn, position, Arity, Closure, MalArgs, MalErr, MalMap, MalRet,
+    MalSeq, MalSet, MalVal,
+};
+
+// read
+fn read(str: &str, env: &Env) -> MalRet {
+    reader::read_str_in(str, Some(env))
+}
+
+// eval
+
+fn qq_iter(elts: &MalSeq) -> MalVal {
+    let mut acc = list![];
+    for elt in elts.iter().rev() {
+        if let List(v, _) = elt {
+            if v.len() == 2 {
+                if let Sym(ref s) = v[0] {
+                    if s == "splice-unquote" {
+                        acc = list![Sym("concat".to_string()), v[1].clone(), acc];
+                        continue;
+                    }
+                }
+            }
+        }
+        acc = list![Sym("cons".to_string()), quasiquote(elt), acc];
+    }
+    acc
+}
+
+fn quasiquote(ast: &MalVal) -> MalVal {
+    match ast {
+        List(v, _) => {
+            if v.len() == 2 {
+                if let Sym(ref s) = v[0] {
+                    if s == "unquote" {
+                        return v[1].clone();
+                    }
+                }
+            }
+            qq_iter(v)
+        }
+        Vector(v, _) => list![Sym("vec".to_string()), qq_iter(v)],
+        Hash(_, _) | Set(_, _) | Sym(_) => list![Sym("quote".to_string()), ast.clone()],
+        _ => ast.clone(),
+    }
+}
+
+fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
+    match ast {
+        List(v, _) => match v.head() {
+            Some(head @ Sym(ref s)) => match env_find(env, s) {
+                Some(e) => match env_get(&e, head) {
+                    Ok(MalFunc(c, meta)) if c.is_macro => {
+                        Some((MalFunc(c, meta), v.iter().skip(1).cloned().collect()))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn macroexpand(mut ast: MalVal, env: &Env) -> (bool, MalRet) {
+    let mut was_expanded = false;
+    while let Some((mf, args)) = is_macro_call(&ast, env) {
+        //println!("macroexpand 1: {:?}", ast);
+        ast = match mf.apply(args) {
+            Err(e) => return (false, Err(e)),
+            Ok(a) => a,
+        };
+        //println!("macroexpand 2: {:?}", ast);
+        was_expanded = true;
+    }
+    (was_expanded, Ok(ast))
+}
+
+fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
+    match ast {
+        Sym(_) => Ok(env_get(env, ast)?),
+        // lists are calls, which eval_form evaluates itself
+        Vector(v, _) => {
+            let mut lst: MalArgs = vec![];
+            for a in v.iter() {
+                lst.push(eval(a.clone(), env.clone())?)
+            }
+            Ok(vector!(lst))
+        }
+        Hash(hm, _) => {
+            let mut new_hm = MalMap::default();
+            for (k, v) in hm.iter() {
+                new_hm.insert(eval(k.clone(), env.clone())?, eval(v.clone(), env.clone())?);
+            }
+            Ok(Hash(new_hm, Rc::new(Nil)))
+        }
+        Set(hs, _) => {
+            let mut new_hs = MalSet::default();
+            for v in hs.iter() {
+                new_hs.insert(eval(v.clone(), env.clone())?);
+            }
+            Ok(Set(new_hs, Rc::new(Nil)))
+        }
+        _ => Ok(ast.clone()),
+    }
+}
+
+// Special forms check how many operands they are given as calls check
+// their arguments
+fn check_operands(l: &MalSeq, name: &str, arity: Arity) -> Result<(), MalErr> {
+    if arity.accepts(l.len() - 1) {
+        Ok(())
+    } else {
+        Err(arity_error(name, l.len() - 1, arity))
+    }
+}
+
+// fn* parameters are symbols, with an optional & just before the last
+fn check_params(params: &MalVal) -> Result<(), MalErr> {
+    match params {
+        List(p, _) | Vector(p, _) => {
+            for (i, b) in p.iter().enumerate() {
+                match b {
+                    Sym(s) if s == "&" && (i + 2 != p.len() || p[i + 1] == *b) => {
+                        return Err(ErrString(
+                            "fn*: & must be followed by exactly one parameter".to_string(),
+                        ));
+                    }
+                    Sym(_) => {}
+                    _ => return Err(ErrString("fn*: parameters must be symbols".to_string())),
+                }
+            }
+            Ok(())
+        }
+        _ => Err(ErrString(
+            "fn*: parameters must be a list or vector".to_string(),
+        )),
+    }
+}
+
+// Errors are tagged with the position of the innermost form being
+// evaluated that the reader recorded one for
+fn eval(ast: MalVal, env: Env) -> MalRet {
+    let mut pos = None;
+    eval_form(ast, env, &mut pos).map_err(|e| match (e, pos) {
+        (e @ ErrAt(_, _), _) | (e, None) => e,
+        (e, Some(pos)) => ErrAt(Box::new(e), pos),
+    })
+}
+
+fn eval_form(mut ast: MalVal, mut env: Env, pos: &mut Option<MalVal>) -> MalRet {
+    let ret: MalRet;
+
+    'tco: loop {
+        if let Some(p) = position(&ast) {
+            *pos = Some(p);
+        }
+        ret = match ast.clone() {
+            List(l, _) => {
+                if l.is_empty() {
+                    return Ok(ast);
+                }
+                match macroexpand(ast.clone(), &env) {
+                    (true, Ok(new_ast)) => {
+                        ast = new_ast;
+                        continue 'tco;
+                    }
+                    (_, Err(e)) => return Err(e),
+                    _ => (),
+                }
+
+                if l.is_empty() {
+                    return Ok(ast);
+                }
+                let a0 = &l[0];
+                match a0 {
+                    Sym(ref a0sym) if a0sym == "def!" => {
+                        check_operands(&l, a0sym, Arity::exactly(2))?;
+                        env_set(&env, l[1].clone(), eval(l[2].clone(), env.clone())?)
+                    }
+                    Sym(ref a0sym) if a0sym == "let*" => {
+                        check_operands(&l, a0sym, Arity::exactly(2))?;
+                        env = env_new(Some(env.clone()));
+                        let (a1, a2) = (l[1].clone(), l[2].clone());
+                        match a1 {
+                            List(ref binds, _) | Vector(ref binds, _) => {
+                                if !binds.len().is_multiple_of(2) {
+                                    return error("let* with an odd number of binding forms");
+                                }
+                                for (b, e) in binds.iter().tuples() {
+                                    match b {
+                                        Sym(_) => {
+                                            let _ = env_set(
+                                                &env,
+                                                b.clone(),
+                                                eval(e.clone(), env.clone())?,
+                                            );
+                                        }
+                                        _ => {
+                                            return error("let* with non-Sym binding");
+                                        }
+                                    }
+                                }
+                            }
+                            _ => {
+                                return error("let* with non-List bindings");
+                            }
+                        };
+                        ast = a2;
+                        continue 'tco;
+                    }
+                    Sym(ref a0sym) if a0sym == "quote" => {
+                        check_operands(&l, a0sym, Arity::exactly(1))?;
+                        Ok(l[1].clone())
+                    }
+                    Sym(ref a0sym) if a0sym == "quasiquoteexpand" => {
+                        check_operands(&l, a0sym, Arity::exactly(1))?;
+                        Ok(quasiquote(&l[1]))
+                    }
+                    Sym(ref a0sym) if a0sym == "quasiquote" => {
+                        check_operands(&l, a0sym, Arity::exactly(1))?;
+                        ast = quasiquote(&l[1]);
+                        continue 'tco;
+                    }
+                    Sym(ref a0sym) if a0sym == "defmacro!" => {
+                        check_operands(&l, a0sym, Arity::exactly(2))?;
+                        let (a1, a2) = (l[1].clone(), l[2].clone());
+                        let r = eval(a2, env.clone())?;
+                        match r {
+                            MalFunc(c, _) => Ok(env_set(
+                                &c.env,
+                                a1.clone(),
+                                MalFunc(
+                                    Rc::new(Closure {
+                                        eval: c.eval,
+                                        ast: c.ast.clone(),
+                                        env: c.env.clone(),
+                                        params: c.params.clone(),
+                                        is_macro: true,
+                                    }),
+                                    Rc::new(Nil),
+                                ),
+                            )?),
+                            _ => error("set_macro on non-function"),
+                        }
+                    }
+                    Sym(ref a0sym) if a0sym == "macroexpand" => {
+                        check_operands(&l, a0sym, Arity::exactly(1))?;
+                        match macroexpand(l[1].clone(), &env) {
+                            (_, Ok(new_ast)) => Ok(new_ast),
+                            (_, e) => return e,
+                        }
+                    }
+                    Sym(ref a0sym) if a0sym == "try*" => {
+                        check_operands(&l, a0sym, Arity::between(1, 2))?;
+                        let catch = match l.get(2) {
+                            Some(List(c, _))
+                                if c.len() == 3 && c[0] == Sym("catch*".to_string()) =>
+                            {
+                                match c[1] {
+                                    Sym(_) => Some(c),
+                                    _ => {
+                                        return error(
+                                            "catch*: the exception must be bound to a symbol",
+                                        )
+                                    }
+                                }
+                            }
+                            Some(_) => return error("invalid catch block"),
+                            None => None,
+                        };
+                        match (eval(l[1].clone(), env.clone()), catch) {
+                            (Err(ref e), Some(c)) => {
+                                let catch_env = env_new(Some(env.clone()));
+                                env_set(&catch_env, c[1].clone(), e.value())?;
+                                eval(c[2].clone(), catch_env)
+                            }
+                            (res, _) => res,
+                        }
+                    }
+                    Sym(ref a0sym) if a0sym == "do" => {
+                        if l.len() == 1 {
+                            return Ok(Nil);
+                        }
+                        for form in l.iter().skip(1).take(l.len() - 2) {
+                            eval(form.clone(), env.clone())?;
+                        }
+                        ast = l[l.len() - 1].clone();
+                        continue 'tco;
+                    }
+                    Sym(ref a0sym) if a0sym == "if" => {
+                        check_operands(&l, a0sym, Arity::between(2, 3))?;
+                        let cond = eval(l[1].clone(), env.clone())?;
+                        match cond {
+                            Bool(false) | Nil if l.len() >= 4 => {
+                                ast = l[3].clone();
+                                continue 'tco;
+                            }
+                            Bool(false) | Nil => Ok(Nil),
+                            _ if l.len() >= 3 => {
+                                ast = l[2].clone();
+                                continue 'tco;
+                            }
+                            _ => Ok(Nil),
+                        }
+                    }
+                    Sym(ref a0sym) if a0sym == "fn*" => {
+                        check_operands(&l, a0sym, Arity::exactly(2))?;
+                        let (a1, a2) = (l[1].clone(), l[2].clone());
+                        check_params(&a1)?;
+                        Ok(MalFunc(
+                            Rc::new(Closure {
+                                eval,
+                                ast: Rc::new(a2),
+                                env,
+                                params: Rc::new(a1),
+                                is_macro: false,
+                            }),
+                            Rc::new(Nil),
+                        ))
+                    }
+                    Sym(ref a0sym) if a0sym == "eval" => {
+                        check_operands(&l, a0sym, Arity::exactly(1))?;
+                        ast = eval(l[1].clone(), env.clone())?;
+                        while let Some(ref e) = env.clone().outer {
+                            env = e.clone();
+                        }
+                        continue 'tco;
+                    }
+                    // the arguments go straight into a MalArgs, without
+                    // building an evaluated list to take them back out of
+                    _ => {
+                        let f = eval(a0.clone(), env.clone())?;
+                        let args = l
+                            .iter()
+                            .skip(1)
+                            .map(|a| eval(a.clone(), env.clone()))
+                            .collect::<Result<MalArgs, MalErr>>()?;
+                        match f {
+                            Func(_, _) | Keyword(_) => f.apply(args),
+                            MalFunc(c, _) => {
+                                env = env_bind(Some(c.env.clone()), (*c.params).clone(), args)?;
+                                ast = (*c.ast).clone();
+                                continue 'tco;
+                            }
+                            _ => error("attempt to call non-function"),
+                        }
+                    }
+                }
+            }
+            // code built by lazy sequence functions evaluates like a list
+            LazySeq(_) => {
+                ast = list!(ast.seq_vec()?);
+                continue 'tco;
+            }
+            _ => eval_ast(&ast, &env),
+        };
+
+        break;
+    } // end 'tco loop
+
+    ret
+}
+
+// print
+fn print(ast: &MalVal, env: &Env) -> Result<String, MalErr> {
+    let limits = crate::core::print_limits(env);
+    ast.pr_pretty(crate::core::right_margin(env), limits)
+}
+
+fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
+    ep(read(str, env)?, env)
+}
+
+fn ep(ast: MalVal, env: &Env) -> Result<String, MalErr> {
+    let exp = eval(ast, env.clone())?;
+    print(&exp, env)
+}
+
+// core.mal: defined using the language itself
+const PRELUDE: &[&str] = &[
+    "(def! *host-language* \"rust\")",
+    "(def! *data-readers* {})",
+    "(def! *print-right-margin* 72)",
+    "(def! *print-length* nil)",
+    "(def! *print-level* nil)",
+    "(def! not (fn* (a) (if a false true)))",
+    "(defmacro! var (fn* (sym) (if (symbol? sym) sym (throw \"var: expected a symbol\"))))",
+    "(defmacro! lazy-seq (fn* (& body) `(lazy-seq* (fn* () (do ~@body)))))",
+    "(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))",
+];
+
+// Evaluates the forms in a file one at a time, so errors report the
+// position they were read from. Each form is read once the ones before
+// it have run, which lets a file define *data-readers* for itself.
+fn load_file(path: &str, env: &Env) -> MalRet {
+    let src = match std::fs::read_to_string(path) {
+        Ok(src) => src,
+        Err(e) => return error(&format!("{}: {}", path, e)),
+    };
+    for form in StreamReader::new(src.as_bytes(), Some(path), Some(env)) {
+        eval(form?, env.clone())?;
+    }
+    Ok(Nil)
+}
+
+type EnvFn = fn(MalArgs, &Env) -> MalRet;
+
+// A builtin that works in the global environment. It keeps a weak
+// reference, as the environment holds on to the builtin.
+fn env_fn(env: &Env, name: &'static str, arity: Arity, f: EnvFn) -> MalVal {
+    let root = Rc::downgrade(env);
+    native_fn(name, arity, move |a| match root.upgrade() {
+        Some(env) => f(a, &env),
+        None => error(&format!("{}: interpreter has been dropped", name)),
+    })
+}
+
+/// A mal environment with the core library loaded, for embedding mal in
+/// a Rust program.
+///
+/// ```
+/// use mal::types::{native_fn, Arity, MalVal};
+/// use mal::Interpreter;
+///
+/// let mal = Interpreter::new();
+/// let offset = 10;
+/// mal.define(
+///     "offset",
+///     native_fn("offset", Arity::at_least(1), move |a| match a[0] {
+///         MalVal::Int(i) => Ok(MalVal::Int(i + offset)),
+///         _ => mal::types::error("offset: expected an integer"),
+///     }),
+/// );
+/// assert_eq!(mal.rep("(offset 5)").unwrap(), "15");
+/// ```
+pub struct Interpreter {
+    env: Env,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        let env = env_new(None);
+        for (k, v) in crate::core::ns() {
+            env_sets(&env, k, v);
+        }
+        env_sets(&env, "*ARGV*", list![]);
+        // builtins that work in the global environment
+        let env_fns: [(&'static str, Arity, EnvFn); 9] = [
+            ("load-file", Arity::exactly(1), |a, env| match &a[0] {
+                Str(path) => load_file(path, env),
+                _ => error("load-file: path must be a string"),
+            }),
+            ("read-string", Arity::exactly(1), |a, env| {
+                crate::core::read_string(a, env)
+            }),
+            ("read-all-string", Arity::between(1, 2), |a, env| {
+                crate::core::read_all_string(a, env)
+            }),
+            ("pr-str", Arity::at_least(0), |a, env| {
+                Ok(Str(crate::core::pr_args(&a, true, " ", env)?))
+            }),
+            ("str", Arity::at_least(0), |a, env| {
+                Ok(Str(crate::core::pr_args(&a, false, "", env)?))
+            }),
+            ("prn", Arity::at_least(0), |a, env| {
+                println!("{}", crate::core::pr_args(&a, true, " ", env)?);
+                Ok(Nil)
+            }),
+            ("println", Arity::at_least(0), |a, env| {
+                println!("{}", crate::core::pr_args(&a, false, " ", env)?);
+                Ok(Nil)
+            }),
+            ("pprint-str", Arity::between(1, 2), |a, env| {
+                crate::core::pprint_str(a, env)
+            }),
+            ("pprint", Arity::between(1, 2), |a, env| {
+                crate::core::pprint(a, env)
+            }),
+        ];
+        for (name, arity, f) in env_fns {
+            env_sets(&env, name, env_fn(&env, name, arity, f));
+        }
+        for form in PRELUDE {
+            rep(form, &env).expect("prelude failed to evaluate");
+        }
+        Interpreter { env }
+    }
+
+    /// The global environment that definitions are made in
+    pub fn env(&self) -> &Env {
+        &self.env
+    }
+
+    /// Reads and evaluates every form in s, returning the last value.
+    /// Input is lexed only once, so many forms on one line read as quickly
+    /// as they would one to a line.
+    ///
+    /// ```
+    /// use std::time::{Duration, Instant};
+    ///
+    /// let mal = mal::Interpreter::new();
+    /// let src: String = (0..20000).map(|i| format!("(def! x{} {}) ", i, i)).collect();
+    /// let start = Instant::now();
+    /// assert_eq!(mal.eval_str(&src).unwrap().pr_str(true), "19999");
+    /// assert_eq!(mal.rep("(+ x0 x12345)").unwrap(), "12345");
+    /// assert!(start.elapsed() < Duration::from_secs(10));
+    /// ```
+    pub fn eval_str(&self, s: &str) -> MalRet {
+        let mut res = Nil;
+        for form in StreamReader::new(s.as_bytes(), None, Some(&self.env)) {
+            res = eval(form?, self.env.clone())?;
+        }
+        Ok(res)
+    }
+
+    /// Reads, evaluates and prints a single form, as the REPL does
+    pub fn rep(&self, s: &str) -> Result<String, MalErr> {
+        rep(s, &self.env)
+    }
+
+    /// Evaluates a form that has already been read
+    pub fn eval(&self, form: MalVal) -> MalRet {
+        eval(form, self.env.clone())
+    }
+
+    /// Evaluates and prints a form that has already been read
+    pub fn ep(&self, form: MalVal) -> Result<String, MalErr> {
+        ep(form, &self.env)
+    }
+
+    /// Evaluates every form in a file
+    pub fn eval_file(&self, path: &str) -> MalRet {
+        self.call("load-file", vec![Str(path.to_string())])
+    }
+
+    /// Binds a value, such as a native function, in the global environment
+    pub fn define(&self, name: &str, val: MalVal) {
+        env_sets(&self.env, name, val);
+    }
+
+    /// Calls the function bound to name in the global environment
+    pub fn call(&self, name: &str, args: MalArgs) -> MalRet {
+        env_get(&self.env, &Sym(name.to_string()))?.apply(args)
+    }
+}
@@ -0,0 +1,27 @@
+//! The mal interpreter as a library, so that it can be embedded in other
+//! Rust programs. The step binaries are thin wrappers around it.
+
+#[macro_use]
+extern crate lazy_static;
+extern crate fnv;
+extern crate im_rc;
+extern crate itertools;
+extern crate num_bigint;
+extern crate num_rational;
+extern crate num_traits;
+extern crate regex;
+extern crate rustyline;
+
+#[macro_use]
+pub mod types;
+pub mod env;
+pub mod printer;
+pub mod reader;
+pub mod seq;
+#[macro_use]
+pub mod core;
+pub mod interpreter;
+pub mod map;
+pub mod repl;
+
+pub use crate::interpreter::Interpreter;
@@ -0,0 +1,245 @@
+//! The entries of a hash map or set. Small ones, which most maps are, are
+//! a plain shared array searched in order, like Clojure's array maps;
+//! bigger ones are a persistent hash array mapped trie, so that assoc,
+//! dissoc and get stay cheap however big they grow.
+
+use std::hash::BuildHasherDefault;
+use std::iter::FromIterator;
+use std::rc::Rc;
+
+use fnv::FnvHasher;
+
+use crate::types::MalVal;
+
+// The most entries kept in an array. Finding a key in one compares it with
+// each key in turn, which at this size is about as quick as hashing it.
+const SMALL_LEN: usize = 8;
+
+// FNV keeps the iteration order stable from run to run
+type Hamt<V> = im_rc::HashMap<MalVal, V, BuildHasherDefault<FnvHasher>>;
+
+// Keys mapped to values. Small tables iterate in the order their keys
+// were first added, big ones in the order of their hashes
+#[derive(Clone)]
+pub struct Table<V: Clone>(Repr<V>);
+
+#[derive(Clone)]
+enum Repr<V: Clone> {
+    Small(Rc<Vec<(MalVal, V)>>),
+    Big(Rc<Hamt<V>>),
+}
+
+pub type MalMap = Table<MalVal>;
+
+impl<V: Clone> Table<V> {
+    pub fn new() -> Table<V> {
+        Table(Repr::Small(Rc::new(vec![])))
+    }
+
+    pub fn len(&self) -> usize {
+        match self.0 {
+            Repr::Small(ref s) => s.len(),
+            Repr::Big(ref m) => m.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, k: &MalVal) -> Option<&V> {
+        match self.0 {
+            Repr::Small(ref s) => s.iter().find(|(k2, _)| k2 == k).map(|(_, v)| v),
+            Repr::Big(ref m) => m.get(k),
+        }
+    }
+
+    pub fn contains_key(&self, k: &MalVal) -> bool {
+        self.get(k).is_some()
+    }
+
+    pub fn insert(&mut self, k: MalVal, v: V) {
+        match self.0 {
+            Repr::Small(ref mut s) => match s.iter().position(|(k2, _)| *k2 == k) {
+                Some(i) => Rc::make_mut(s)[i].1 = v,
+                None if s.len() < SMALL_LEN => {
+                    // most maps are never added to once built, so leave no
+                    // room to spare
+                    let s = Rc::make_mut(s);
+                    s.reserve_exact(1);
+                    s.push((k, v))
+                }
+                None => {
+                    let mut m: Hamt<V> = s.iter().cloned().collect();
+                    m.insert(k, v);
+                    self.0 = Repr::Big(Rc::new(m));
+                }
+            },
+            Repr::Big(ref mut m) => {
+                Rc::make_mut(m).insert(k, v);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, k: &MalVal) {
+        match self.0 {
+            Repr::Small(ref mut s) => {
+                if let Some(i) = s.iter().position(|(k2, _)| k2 == k) {
+                    Rc::make_mut(s).remove(i);
+                }
+            }
+            Repr::Big(ref mut m) => {
+                Rc::make_mut(m).remove(k);
+            }
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, V> {
+        match self.0 {
+            Repr::Small(ref s) => Iter::Small(s.iter()),
+            Repr::Big(ref m) => Iter::Big(m.iter()),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &MalVal> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, v)| v)
+    }
+}
+
+impl<V: Clone> Default for Table<V> {
+    fn default() -> Table<V> {
+        Table::new()
+    }
+}
+
+impl<V: Clone + std::fmt::Debug> std::fmt::Debug for Table<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+// Equal whatever order the entries were added in
+impl<V: Clone + PartialEq> PartialEq for Table<V> {
+    fn eq(&self, other: &Table<V>) -> bool {
+        self.len() == other.len() && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<V: Clone> FromIterator<(MalVal, V)> for Table<V> {
+    fn from_iter<I: IntoIterator<Item = (MalVal, V)>>(iter: I) -> Table<V> {
+        let mut t = Table::new();
+        t.extend(iter);
+        t
+    }
+}
+
+impl<V: Clone> Extend<(MalVal, V)> for Table<V> {
+    fn extend<I: IntoIterator<Item = (MalVal, V)>>(&mut self, iter: I) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+pub enum Iter<'a, V: Clone> {
+    Small(std::slice::Iter<'a, (MalVal, V)>),
+    Big(im_rc::hashmap::Iter<'a, MalVal, V>),
+}
+
+impl<'a, V: Clone> Iterator for Iter<'a, V> {
+    type Item = (&'a MalVal, &'a V);
+
+    fn next(&mut self) -> Option<(&'a MalVal, &'a V)> {
+        match self {
+            Iter::Small(it) => it.next().map(|(k, v)| (k, v)),
+            Iter::Big(it) => it.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            Iter::Small(it) => it.size_hint(),
+            Iter::Big(it) => it.size_hint(),
+        }
+    }
+}
+
+// A set is a table of its elements, each mapped to nothing
+#[derive(Clone, Default, PartialEq)]
+pub struct MalSet(Table<()>);
+
+impl MalSet {
+    pub fn new() -> MalSet {
+        MalSet(Table::new())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, v: &MalVal) -> bool {
+        self.0.contains_key(v)
+    }
+
+    pub fn insert(&mut self, v: MalVal) {
+        self.0.insert(v, ())
+    }
+
+    pub fn remove(&mut self, v: &MalVal) {
+        self.0.remove(v)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MalVal> {
+        self.0.keys()
+    }
+
+    pub fn unions<I: IntoIterator<Item = MalSet>>(sets: I) -> MalSet {
+        let mut sets = sets.into_iter();
+        let first = sets.next().unwrap_or_default();
+        sets.fold(first, |mut acc, s| {
+            acc.extend(s.iter().cloned());
+            acc
+        })
+    }
+
+    pub fn intersection(self, other: MalSet) -> MalSet {
+        self.iter().filter(|v| other.contains(v)).cloned().collect()
+    }
+
+    pub fn relative_complement(self, other: MalSet) -> MalSet {
+        self.iter()
+            .filter(|v| !other.contains(v))
+            .cloned()
+            .collect()
+    }
+
+    pub fn is_subset(&self, other: &MalSet) -> bool {
+        self.iter().all(|v| other.contains(v))
+    }
+}
+
+impl std::fmt::Debug for MalSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl FromIterator<MalVal> for MalSet {
+    fn from_iter<I: IntoIterator<Item = MalVal>>(iter: I) -> MalSet {
+        MalSet(iter.into_iter().map(|v| (v, ())).collect())
+    }
+}
+
+impl Extend<MalVal> for MalSet {
+    fn extend<I: IntoIterator<Item = MalVal>>(&mut self, iter: I) {
+        self.0.extend(iter.into_iter().map(|v| (v, ())))
+    }
+}
@@ -1,61 +1,481 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use self::Doc::{Align, Cat, Group, Line, Nest, Text};
 use crate::types::MalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::MalVal::{
+    Atom, BigInt, Bool, Char, Float, Func, Hash, Int, Keyword, LazySeq, List, MalFunc, Nil,
+    Position, Ratio, Regex, Set, Str, Sym, Tagged, Vector,
+};
+use crate::types::{format_error, MalArgs, MalErr};
 
 fn escape_str(s: &str) -> String {
     s.chars()
         .map(|c| match c {
             '"' => "\\\"".to_string(),
             '\n' => "\\n".to_string(),
+            '\t' => "\\t".to_string(),
+            '\r' => "\\r".to_string(),
+            '\0' => "\\0".to_string(),
             '\\' => "\\\\".to_string(),
+            c if c.is_control() => format!("\\u{:04x}", c as u32),
             _ => c.to_string(),
         })
         .collect::<Vec<String>>()
         .join("")
 }
 
-impl MalVal {
-    pub fn pr_str(&self, print_readably: bool) -> String {
-        match self {
+fn pr_char(c: char) -> String {
+    match c {
+        '\n' => String::from("\\newline"),
+        ' ' => String::from("\\space"),
+        '\t' => String::from("\\tab"),
+        '\r' => String::from("\\return"),
+        '\u{8}' => String::from("\\backspace"),
+        '\u{c}' => String::from("\\formfeed"),
+        c if c.is_control() => format!("\\u{:04x}", c as u32),
+        c => format!("\\{}", c),
+    }
+}
+
+fn pr_float(f: f64) -> String {
+    if f.is_nan() {
+        String::from("##NaN")
+    } else if f.is_infinite() {
+        String::from(if f > 0.0 { "##Inf" } else { "##-Inf" })
+    } else {
+        // Debug formatting always keeps a '.' or an exponent, so the
+        // result reads back as a Float rather than an Int
+        format!("{:?}", f)
+    }
+}
+
+// Limits on how much of a value gets printed, as set by *print-length*
+// and *print-level*; None leaves that side unlimited
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PrintLimits {
+    // the most elements printed from any one collection
+    pub length: Option<usize>,
+    // how deeply collections are printed inside each other
+    pub level: Option<usize>,
+}
+
+// What stands in for whatever the limits leave out
+const ELLIPSIS: &str = "...";
+
+// The state of printing one value
+struct Printer {
+    readably: bool,
+    limits: PrintLimits,
+    // the atoms being printed, outermost first; meeting one of them again
+    // inside itself is a cycle
+    atoms: Vec<*const RefCell<MalVal>>,
+    // the first error met realizing a lazy seq, printed in its place
+    error: Option<MalErr>,
+}
+
+impl Printer {
+    fn new(readably: bool, limits: PrintLimits) -> Printer {
+        Printer {
+            readably,
+            limits,
+            atoms: vec![],
+            error: None,
+        }
+    }
+
+    fn finish(&mut self, s: String) -> Result<String, MalErr> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(s),
+        }
+    }
+
+    // Whether collections at depth are too deep to print
+    fn too_deep(&self, depth: usize) -> bool {
+        self.limits.level.is_some_and(|level| depth >= level)
+    }
+
+    // The items to print, and whether there were more than that
+    fn take<T>(&self, items: impl Iterator<Item = T>) -> (Vec<T>, bool) {
+        let mut items = items.peekable();
+        let length = self.limits.length.unwrap_or(usize::MAX);
+        let taken = items.by_ref().take(length).collect();
+        (taken, items.peek().is_some())
+    }
+
+    // Like take, realizing only as much of a lazy seq as gets printed
+    fn take_seq(&mut self, seq: &MalVal) -> Option<(MalArgs, bool)> {
+        let length = self.limits.length.unwrap_or(usize::MAX);
+        let mut taken = vec![];
+        let mut cur = seq.clone();
+        loop {
+            match cur.seq_step() {
+                Ok(Some(_)) if taken.len() == length => return Some((taken, true)),
+                Ok(Some((first, rest))) => {
+                    taken.push(first);
+                    cur = rest;
+                }
+                Ok(None) => return Some((taken, false)),
+                Err(e) => {
+                    self.error.get_or_insert(e);
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn pr_error(&self) -> String {
+        match self.error {
+            Some(ref e) => format!("#<error {}>", format_error(e.clone())),
+            None => String::new(),
+        }
+    }
+
+    fn pr(&mut self, v: &MalVal, depth: usize) -> String {
+        let readably = self.readably;
+        match v {
+            List(..) | Vector(..) | Hash(..) | Set(..) | LazySeq(_) if self.too_deep(depth) => {
+                ELLIPSIS.to_string()
+            }
             Nil => String::from("nil"),
             Bool(true) => String::from("true"),
             Bool(false) => String::from("false"),
             Int(i) => format!("{}", i),
-            //Float(f)    => format!("{}", f),
-            Str(s) => {
-                if s.starts_with("\u{29e}") {
-                    format!(":{}", &s[2..])
-                } else if print_readably {
-                    format!("\"{}\"", escape_str(s))
-                } else {
-                    s.clone()
-                }
-            }
+            BigInt(b) => b.to_string(),
+            Ratio(r) => r.to_string(),
+            Float(f) => pr_float(*f),
+            Str(s) if readably => format!("\"{}\"", escape_str(s)),
+            Str(s) => s.clone(),
+            Char(c) if readably => pr_char(*c),
+            Char(c) => c.to_string(),
             Sym(s) => s.clone(),
-            List(l, _) => pr_seq(&**l, print_readably, "(", ")", " "),
-            Vector(l, _) => pr_seq(&**l, print_readably, "[", "]", " "),
+            Keyword(k) => format!(":{}", k),
+            List(l, _) => {
+                let items = self.take(l.iter());
+                self.pr_items(items, "(", ")", depth)
+            }
+            Vector(l, _) => {
+                let items = self.take(l.iter());
+                self.pr_items(items, "[", "]", depth)
+            }
             Hash(hm, _) => {
-                let l: Vec<MalVal> = hm
-                    .iter()
-                    .flat_map(|(k, v)| vec![Str(k.to_string()), v.clone()])
+                let (entries, more) = self.take(hm.iter());
+                let items = entries.into_iter().flat_map(|(k, v)| vec![k, v]);
+                self.pr_items((items.collect(), more), "{", "}", depth)
+            }
+            Set(hs, _) => {
+                let items = self.take(hs.iter());
+                self.pr_items(items, "#{", "}", depth)
+            }
+            LazySeq(_) => match self.take_seq(v) {
+                Some((l, more)) => self.pr_items((l.iter().collect(), more), "(", ")", depth),
+                None => self.pr_error(),
+            },
+            MalFunc(c, _) => format!(
+                "(fn* {} {})",
+                self.pr_readably(&c.params, depth + 1),
+                self.pr_readably(&c.ast, depth + 1)
+            ),
+            Atom(a) if self.atoms.contains(&Rc::as_ptr(a)) => String::from("#<cycle>"),
+            Atom(a) => {
+                self.atoms.push(Rc::as_ptr(a));
+                let s = format!("(atom {})", self.pr_readably(&a.borrow(), depth + 1));
+                self.atoms.pop();
+                s
+            }
+            Tagged(t, v) if readably => format!("#{} {}", t, self.pr(v, depth)),
+            Tagged(_, v) => self.pr(v, depth),
+            Position(p) => self.pr(&p.to_map(), depth),
+            Func(nf, _) if nf.name.is_empty() => "#<fn>".to_string(),
+            Func(nf, _) => format!("#<fn {}>", nf.name),
+            Regex(r) if readably => format!("#\"{}\"", r.as_str().replace('"', "\\\"")),
+            Regex(r) => r.as_str().to_string(),
+        }
+    }
+
+    fn pr_readably(&mut self, v: &MalVal, depth: usize) -> String {
+        let readably = std::mem::replace(&mut self.readably, true);
+        let s = self.pr(v, depth);
+        self.readably = readably;
+        s
+    }
+
+    fn pr_items(
+        &mut self,
+        (items, more): (Vec<&MalVal>, bool),
+        start: &str,
+        end: &str,
+        depth: usize,
+    ) -> String {
+        let mut strs: Vec<String> = items.into_iter().map(|v| self.pr(v, depth + 1)).collect();
+        if more {
+            strs.push(ELLIPSIS.to_string());
+        }
+        format!("{}{}{}", start, strs.join(" "), end)
+    }
+}
+
+impl MalVal {
+    pub fn pr_str(&self, print_readably: bool) -> String {
+        Printer::new(print_readably, PrintLimits::default()).pr(self, 0)
+    }
+
+    // Like pr_str, but leaving out what limits leave out, and failing
+    // with the first error met realizing a lazy seq
+    pub fn pr_limited(&self, print_readably: bool, limits: PrintLimits) -> Result<String, MalErr> {
+        let mut printer = Printer::new(print_readably, limits);
+        let s = printer.pr(self, 0);
+        printer.finish(s)
+    }
+}
+
+// The right margin pretty printing keeps to when none is given
+pub const DEFAULT_RIGHT_MARGIN: usize = 72;
+
+// A document for the pretty printer (after Wadler's "A prettier printer").
+// A Line is a space when the group around it fits in the width left, and
+// a line break otherwise.
+enum Doc {
+    Text(String),
+    Line,
+    Cat(Vec<Doc>),
+    // indents line breaks inside by a further n columns
+    Nest(usize, Box<Doc>),
+    // indents line breaks inside to the column it starts at
+    Align(Box<Doc>),
+    Group(Box<Doc>),
+}
+
+fn text(s: &str) -> Doc {
+    Text(s.to_string())
+}
+
+fn lines(docs: Vec<Doc>) -> Doc {
+    let mut res = vec![];
+    for (i, d) in docs.into_iter().enumerate() {
+        if i > 0 {
+            res.push(Line);
+        }
+        res.push(d);
+    }
+    Cat(res)
+}
+
+// Data: when broken, one element per line, lined up after the bracket
+fn seq_doc(start: &str, docs: Vec<Doc>, end: &str) -> Doc {
+    Group(Box::new(Cat(vec![
+        text(start),
+        Align(Box::new(lines(docs))),
+        text(end),
+    ])))
+}
+
+// (head first ...body), with the body indented two columns past the
+// opening paren when broken
+fn body_doc(head: &str, first: Option<Doc>, body: Vec<Doc>) -> Doc {
+    let mut docs = vec![text("("), text(head)];
+    if let Some(first) = first {
+        docs.push(text(" "));
+        docs.push(first);
+    }
+    let body = body.into_iter().flat_map(|d| vec![Line, d]).collect();
+    docs.push(Nest(2, Box::new(Cat(body))));
+    docs.push(text(")"));
+    Group(Box::new(Align(Box::new(Cat(docs)))))
+}
+
+// Whether the rest of the line, starting with doc laid out flat and
+// going on with what is left on the stack, fits in width columns
+fn fits(width: usize, doc: &Doc, rest: &[(usize, bool, &Doc)]) -> bool {
+    let mut width = width as isize;
+    let mut todo: Vec<(bool, &Doc)> = vec![(true, doc)];
+    let mut rest = rest.iter().rev();
+    while width >= 0 {
+        let (flat, doc) = match todo.pop() {
+            Some(next) => next,
+            None => match rest.next() {
+                Some(&(_, flat, doc)) => (flat, doc),
+                None => return true,
+            },
+        };
+        match doc {
+            Text(s) => width -= s.chars().count() as isize,
+            Line if flat => width -= 1,
+            Line => return true,
+            Cat(docs) => todo.extend(docs.iter().rev().map(|d| (flat, d))),
+            Nest(_, d) | Align(d) => todo.push((flat, d)),
+            Group(d) => todo.push((true, d)),
+        }
+    }
+    false
+}
+
+fn layout(doc: &Doc, right_margin: usize) -> String {
+    let mut out = String::new();
+    let mut column = 0;
+    // (indent, flat, doc), the next to lay out on top
+    let mut stack = vec![(0, false, doc)];
+    while let Some((indent, flat, doc)) = stack.pop() {
+        match doc {
+            Text(s) => {
+                out.push_str(s);
+                column += s.chars().count();
+            }
+            Line if flat => {
+                out.push(' ');
+                column += 1;
+            }
+            Line => {
+                out.push('\n');
+                out.push_str(&" ".repeat(indent));
+                column = indent;
+            }
+            Cat(docs) => stack.extend(docs.iter().rev().map(|d| (indent, flat, d))),
+            Nest(n, d) => stack.push((indent + n, flat, d)),
+            Align(d) => stack.push((column, flat, d)),
+            Group(d) => {
+                let flat = flat || fits(right_margin.saturating_sub(column), d, &stack);
+                stack.push((indent, flat, d));
+            }
+        }
+    }
+    out
+}
+
+// The ellipsis goes after the documents of what was printed when the
+// limits left something out. It is only text: as an element it would
+// print as a symbol, and could be laid out as a let* binding or body.
+fn with_ellipsis(mut docs: Vec<Doc>, more: bool) -> Vec<Doc> {
+    if more {
+        docs.push(text(ELLIPSIS));
+    }
+    docs
+}
+
+fn owned((items, more): (Vec<&MalVal>, bool)) -> (MalArgs, bool) {
+    (items.into_iter().cloned().collect(), more)
+}
+
+impl Printer {
+    fn doc(&mut self, v: &MalVal, depth: usize) -> Doc {
+        match v {
+            List(..) | Vector(..) | Hash(..) | Set(..) | LazySeq(_) if self.too_deep(depth) => {
+                text(ELLIPSIS)
+            }
+            List(l, _) => {
+                let (l, more) = owned(self.take(l.iter()));
+                self.list_doc(&l, more, depth)
+            }
+            Vector(l, _) => {
+                let (l, more) = owned(self.take(l.iter()));
+                seq_doc("[", with_ellipsis(self.docs(&l, depth + 1), more), "]")
+            }
+            Set(hs, _) => {
+                let (l, more) = owned(self.take(hs.iter()));
+                seq_doc("#{", with_ellipsis(self.docs(&l, depth + 1), more), "}")
+            }
+            Hash(hm, _) => {
+                let (entries, more) = self.take(hm.iter());
+                let docs = entries
+                    .into_iter()
+                    .map(|(k, v)| self.pair_doc(k, v, depth + 1))
                     .collect();
-                pr_seq(&l, print_readably, "{", "}", " ")
+                seq_doc("{", with_ellipsis(docs, more), "}")
             }
-            Func(f, _) => format!("#<fn {:?}>", f),
-            MalFunc {
-                ast: a, params: p, ..
-            } => format!("(fn* {} {})", p.pr_str(true), a.pr_str(true)),
-            Atom(a) => format!("(atom {})", a.borrow().pr_str(true)),
+            LazySeq(_) => match self.take_seq(v) {
+                Some((l, more)) => self.list_doc(&l, more, depth),
+                None => Text(self.pr_error()),
+            },
+            MalFunc(c, _) => self.list_doc(
+                &[
+                    Sym("fn*".to_string()),
+                    (*c.params).clone(),
+                    (*c.ast).clone(),
+                ],
+                false,
+                depth,
+            ),
+            _ => Text(self.pr(v, depth)),
+        }
+    }
+
+    fn docs(&mut self, l: &[MalVal], depth: usize) -> Vec<Doc> {
+        l.iter().map(|v| self.doc(v, depth)).collect()
+    }
+
+    fn pair_doc(&mut self, k: &MalVal, v: &MalVal, depth: usize) -> Doc {
+        Cat(vec![self.doc(k, depth), text(" "), self.doc(v, depth)])
+    }
+
+    // A key cut off from its value shares its line with the ellipsis
+    fn pairs_doc(&mut self, l: &[MalVal], more: bool, depth: usize) -> Vec<Doc> {
+        let docs = l
+            .chunks(2)
+            .map(|p| match p {
+                [k, v] => self.pair_doc(k, v, depth),
+                [k] if more => Cat(vec![self.doc(k, depth), text(" "), text(ELLIPSIS)]),
+                _ => self.doc(&p[0], depth),
+            })
+            .collect();
+        with_ellipsis(docs, more && l.len().is_multiple_of(2))
+    }
+
+    // more says the limits left elements out after l
+    fn list_doc(&mut self, l: &[MalVal], more: bool, depth: usize) -> Doc {
+        match l {
+            [Sym(s), bindings, body @ ..] if s == "let*" => {
+                let bindings = match bindings {
+                    Vector(b, _) | List(b, _) if !self.too_deep(depth + 1) => {
+                        let (start, end) = if let Vector(..) = bindings {
+                            ("[", "]")
+                        } else {
+                            ("(", ")")
+                        };
+                        let (b, more) = owned(self.take(b.iter()));
+                        let b = self.pairs_doc(&b, more, depth + 2);
+                        seq_doc(start, b, end)
+                    }
+                    _ => self.doc(bindings, depth + 1),
+                };
+                let body = with_ellipsis(self.docs(body, depth + 1), more);
+                body_doc(s, Some(bindings), body)
+            }
+            [Sym(s), params, body @ ..] if s == "fn*" => {
+                let params = self.doc(params, depth + 1);
+                let body = with_ellipsis(self.docs(body, depth + 1), more);
+                body_doc(s, Some(params), body)
+            }
+            [Sym(s), body @ ..] if s == "do" => {
+                let body = with_ellipsis(self.docs(body, depth + 1), more);
+                body_doc(s, None, body)
+            }
+            [Sym(s), clauses @ ..] if s == "cond" => {
+                let clauses = self.pairs_doc(clauses, more, depth + 1);
+                body_doc(s, None, clauses)
+            }
+            // a call, with the arguments lined up after the first one
+            [Sym(s), args @ ..] if !args.is_empty() => {
+                let args = with_ellipsis(self.docs(args, depth + 1), more);
+                Group(Box::new(Cat(vec![
+                    text("("),
+                    text(s),
+                    text(" "),
+                    Align(Box::new(lines(args))),
+                    text(")"),
+                ])))
+            }
+            _ => seq_doc("(", with_ellipsis(self.docs(l, depth + 1), more), ")"),
         }
     }
 }
 
-pub fn pr_seq(
-    seq: &Vec<MalVal>,
-    print_readably: bool,
-    start: &str,
-    end: &str,
-    join: &str,
-) -> String {
-    let strs: Vec<String> = seq.iter().map(|x| x.pr_str(print_readably)).collect();
-    format!("{}{}{}", start, strs.join(join), end)
+impl MalVal {
+    // Prints readably, breaking lines and indenting nested forms to keep
+    // within right_margin columns where possible; fails like pr_limited
+    pub fn pr_pretty(&self, right_margin: usize, limits: PrintLimits) -> Result<String, MalErr> {
+        let mut printer = Printer::new(true, limits);
+        let doc = printer.doc(self, 0);
+        printer.finish(layout(&doc, right_margin))
+    }
 }
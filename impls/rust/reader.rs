@@ -1,113 +1,735 @@
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::Zero;
 use regex::{Captures, Regex};
+use std::io::{BufRead, BufReader, Read};
 use std::rc::Rc;
+use std::str::Chars;
 
-use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{Bool, Int, List, Nil, Str, Sym, Vector};
-use crate::types::{error, hash_map, MalErr, MalRet, MalVal};
+use crate::env::{env_get, Env};
+use crate::types::MalErr::{ErrAt, ErrString};
+use crate::types::MalVal::{
+    Bool, Char, Float, Hash, List, Nil, Position, Set, Str, Sym, Tagged, Vector,
+};
+use crate::types::{
+    _assoc, error, hash_set, int_from_str, keyword, ratio, regex, MalErr, MalMap, MalRet, MalSeq,
+    MalVal, Pos,
+};
 
+// What the lexer saw while scanning a token, so the reader does not have
+// to look at its text again to tell numbers and strings from symbols.
+// Strings and regexes record whether they were closed.
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Number,
+    Str(bool),
+    Regex(bool),
+    Other,
+}
+
+// Lines and columns are counted from 1, columns in characters. The text
+// is borrowed from the source rather than copied out of it, starting at
+// byte offset.
 #[derive(Debug, Clone)]
-struct Reader {
-    tokens: Vec<String>,
+struct Token<'a> {
+    text: &'a str,
+    kind: Kind,
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Reader<'a> {
+    tokens: Vec<Token<'a>>,
     pos: usize,
+    file: Option<Rc<str>>,
+    // set when a form runs past the end of the tokens, so more input
+    // could still complete it
+    incomplete: bool,
+    // inside #(...), which cannot nest
+    in_fn: bool,
+    // where *host-language* and *data-readers* are looked up
+    env: Option<Env>,
+    // reading a reader conditional branch for another host, whose tags
+    // are left unresolved
+    suppress: bool,
 }
 
-impl Reader {
-    fn next(&mut self) -> Result<String, MalErr> {
-        self.pos = self.pos + 1;
-        Ok(self
-            .tokens
-            .get(self.pos - 1)
-            .ok_or(ErrString("underflow".to_string()))?
-            .to_string())
+impl<'a> Reader<'a> {
+    fn new(tokens: Vec<Token<'a>>, file: Option<Rc<str>>, env: Option<&Env>) -> Reader<'a> {
+        Reader {
+            tokens,
+            pos: 0,
+            file,
+            incomplete: false,
+            in_fn: false,
+            env: env.cloned(),
+            suppress: false,
+        }
+    }
+    fn lookup(&self, name: &str) -> Option<MalVal> {
+        env_get(self.env.as_ref()?, &Sym(name.to_string())).ok()
+    }
+    fn next(&mut self) -> Result<&'a str, MalErr> {
+        let token = self.peek()?;
+        self.pos += 1;
+        Ok(token)
+    }
+    fn next_kind(&mut self) -> Result<(&'a str, Kind), MalErr> {
+        let token = self.next()?;
+        Ok((token, self.tokens[self.pos - 1].kind))
+    }
+    fn peek(&mut self) -> Result<&'a str, MalErr> {
+        match self.tokens.get(self.pos) {
+            Some(t) => Ok(t.text),
+            None => {
+                self.incomplete = true;
+                Err(ErrString("underflow".to_string()))
+            }
+        }
+    }
+    // byte offset just past the tokens read so far
+    fn offset(&self) -> usize {
+        match self.pos.checked_sub(1).and_then(|i| self.tokens.get(i)) {
+            Some(t) => t.offset + t.text.len(),
+            None => 0,
+        }
     }
-    fn peek(&self) -> Result<String, MalErr> {
-        Ok(self
-            .tokens
-            .get(self.pos)
-            .ok_or(ErrString("underflow".to_string()))?
-            .to_string())
+    // where the next token is
+    fn position(&self) -> MalVal {
+        self.position_at(self.pos)
+    }
+    fn position_at(&self, idx: usize) -> MalVal {
+        let (line, column) = match self.tokens.get(idx).or(self.tokens.last()) {
+            Some(t) => (t.line, t.column),
+            None => (1, 1),
+        };
+        Position(Pos {
+            line: line as u32,
+            column: column as u32,
+            file: self.file.clone(),
+        })
     }
 }
 
-fn tokenize(str: &str) -> Vec<String> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(
-            r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###
-        )
-        .unwrap();
+fn error_at(e: MalErr, pos: MalVal) -> MalErr {
+    match e {
+        ErrAt(_, _) => e,
+        _ => ErrAt(Box::new(e), pos),
     }
+}
 
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "[]{}('\"`,;)".contains(c)
+}
+
+// Single pass over the characters of the source, tracking the line and
+// column as it goes
+struct Lexer<'a> {
+    src: &'a str,
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn peek(&self) -> Option<char> {
+        self.src[self.offset..].chars().next()
+    }
+
+    fn peek2(&self) -> Option<char> {
+        self.src[self.offset..].chars().nth(1)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn bump_while(&mut self, pred: fn(char) -> bool) {
+        while self.peek().is_some_and(pred) {
+            self.bump();
+        }
+    }
+
+    // the rest of a string, after its opening quote; false if it is
+    // never closed
+    fn string(&mut self) -> bool {
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.bump();
+                    return true;
+                }
+                // an escape that would swallow the newline or the
+                // end of input leaves the string unterminated
+                Some('\\') if self.peek2().is_some_and(|c| c != '\n') => {
+                    self.bump();
+                    self.bump();
+                }
+                Some('\\') | None => return false,
+                Some(_) => {
+                    self.bump();
+                }
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Option<Token<'a>> {
+        loop {
+            self.bump_while(|c| c.is_whitespace() || c == ',');
+            let (start, line, column) = (self.offset, self.line, self.column);
+            let mut kind = Kind::Other;
+            match self.bump()? {
+                '~' if self.peek() == Some('@') => {
+                    self.bump();
+                }
+                '#' if self.peek().is_some_and(|c| "{(_'".contains(c)) => {
+                    self.bump();
+                }
+                '[' | ']' | '{' | '}' | '(' | ')' | '\'' | '`' | '~' | '^' | '@' => {}
+                '#' if self.peek() == Some('"') => {
+                    self.bump();
+                    kind = Kind::Regex(self.string());
+                }
+                '"' => kind = Kind::Str(self.string()),
+                ';' => {
+                    self.bump_while(|c| c != '\n');
+                    continue;
+                }
+                '\\' => {
+                    if self.peek().is_some_and(|c| c != '\n') {
+                        self.bump();
+                    }
+                    self.bump_while(|c| !is_delimiter(c));
+                }
+                c => {
+                    let signed = c == '-' || c == '+';
+                    if c.is_ascii_digit()
+                        || signed && self.peek().is_some_and(|c| c.is_ascii_digit())
+                    {
+                        kind = Kind::Number;
+                    }
+                    self.bump_while(|c| !is_delimiter(c));
+                }
+            }
+            return Some(Token {
+                text: &self.src[start..self.offset],
+                kind,
+                offset: start,
+                line,
+                column,
+            });
+        }
+    }
+}
+
+// line and column are where src starts in the input it was taken from
+fn tokenize(src: &str, line: usize, column: usize) -> Vec<Token<'_>> {
+    let mut lexer = Lexer {
+        src,
+        offset: 0,
+        line,
+        column,
+    };
     let mut res = vec![];
-    for cap in RE.captures_iter(str) {
-        if cap[1].starts_with(";") {
+    while let Some(t) = lexer.next_token() {
+        res.push(t);
+    }
+    res
+}
+
+// \\ and \" stand for the character escaped; any other escape without a
+// meaning of its own is an error rather than quietly losing its backslash
+fn unescape_str(s: &str) -> Result<String, MalErr> {
+    let mut res = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
             continue;
         }
-        res.push(String::from(&cap[1]));
+        match chars.next() {
+            Some('n') => res.push('\n'),
+            Some('t') => res.push('\t'),
+            Some('r') => res.push('\r'),
+            Some('0') => res.push('\0'),
+            Some('u') => res.push(unicode_escape(&mut chars)?),
+            Some(c @ '\\') | Some(c @ '"') => res.push(c),
+            Some(c) => return Err(ErrString(format!("invalid escape \\{} in string", c))),
+            None => {}
+        }
+    }
+    Ok(res)
+}
+
+// The code point after \u, either four hex digits or one to six in braces
+fn unicode_escape(chars: &mut Chars) -> Result<char, MalErr> {
+    let rest = chars.as_str();
+    let (hex, len) = match rest.strip_prefix('{') {
+        Some(r) => match r.find('}') {
+            Some(end) if (1..=6).contains(&end) => (&r[..end], end + 2),
+            _ => ("", 0),
+        },
+        None => (rest.get(..4).unwrap_or(""), 4),
+    };
+    let c = Some(hex)
+        .filter(|h| !h.is_empty() && h.chars().all(|c| c.is_ascii_hexdigit()))
+        .and_then(|h| u32::from_str_radix(h, 16).ok())
+        .and_then(std::char::from_u32);
+    match c {
+        Some(c) => {
+            *chars = rest[len..].chars();
+            Ok(c)
+        }
+        None => Err(ErrString("invalid unicode escape in string".to_string())),
     }
-    res
 }
 
-fn unescape_str(s: &str) -> String {
+// In a regex literal \" stands for a quote; other escapes are left for
+// the regex engine
+fn regex_source(s: &str) -> String {
     lazy_static! {
         static ref RE: Regex = Regex::new(r#"\\(.)"#).unwrap();
     }
-    RE.replace_all(&s, |caps: &Captures| {
-        format!("{}", if &caps[1] == "n" { "\n" } else { &caps[1] })
+    RE.replace_all(s, |caps: &Captures| {
+        (if &caps[1] == "\"" { "\"" } else { &caps[0] }).to_string()
     })
     .to_string()
 }
 
-fn read_atom(rdr: &mut Reader) -> MalRet {
-    lazy_static! {
-        static ref INT_RE: Regex = Regex::new(r"^-?[0-9]+$").unwrap();
-        static ref STR_RE: Regex = Regex::new(r#""(?:\\.|[^\\"])*""#).unwrap();
-    }
-    let token = rdr.next()?;
-    match &token[..] {
-        "nil" => Ok(Nil),
-        "false" => Ok(Bool(false)),
-        "true" => Ok(Bool(true)),
-        _ => {
-            if INT_RE.is_match(&token) {
-                Ok(Int(token.parse().unwrap()))
-            } else if STR_RE.is_match(&token) {
-                Ok(Str(unescape_str(&token[1..token.len() - 1])))
-            } else if token.starts_with("\"") {
-                error("expected '\"', got EOF")
-            } else if token.starts_with(":") {
-                Ok(Str(format!("\u{29e}{}", &token[1..])))
-            } else {
-                Ok(Sym(token.to_string()))
+fn read_char(token: &str) -> MalRet {
+    let mut chars = token.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(Char(c));
+    }
+    match token {
+        "newline" => Ok(Char('\n')),
+        "space" => Ok(Char(' ')),
+        "tab" => Ok(Char('\t')),
+        "return" => Ok(Char('\r')),
+        "backspace" => Ok(Char('\u{8}')),
+        "formfeed" => Ok(Char('\u{c}')),
+        _ if token.len() == 5 && token.starts_with('u') => u32::from_str_radix(&token[1..], 16)
+            .ok()
+            .and_then(std::char::from_u32)
+            .map(Char)
+            .ok_or_else(|| ErrString(format!("invalid unicode character: \\{}", token))),
+        _ => error(&format!("unsupported character: \\{}", token)),
+    }
+}
+
+// Digits, possibly grouped with underscores: "1_000" but not "1__0" or "1_"
+fn is_grouped(s: &str, digit: fn(char) -> bool) -> bool {
+    s.split('_').all(|g| !g.is_empty() && g.chars().all(digit))
+}
+
+// What follows the integer part of a float: a fraction with an optional
+// exponent, or just an exponent
+fn is_float_tail(s: &str) -> bool {
+    let (fraction, exp) = match s.strip_prefix('.') {
+        Some(f) => (true, f.trim_start_matches(|c: char| c.is_ascii_digit())),
+        None => (false, s),
+    };
+    match exp.strip_prefix(|c| c == 'e' || c == 'E') {
+        Some(e) => {
+            let e = e.strip_prefix(|c| c == '-' || c == '+').unwrap_or(e);
+            !e.is_empty() && e.chars().all(|c| c.is_ascii_digit())
+        }
+        None => fraction && exp.is_empty(),
+    }
+}
+
+// A sign, then digits in the radix given by a 0x, 0o, 0b or Nr prefix or
+// else decimal digits, grouped with underscores if need be; a ratio of
+// decimal integers; or a float. None if the token is none of these, in
+// which case it is a symbol.
+fn read_number(token: &str) -> Option<MalRet> {
+    let body = token
+        .strip_prefix(|c| c == '-' || c == '+')
+        .unwrap_or(token);
+    let sign = &token[..token.len() - body.len()];
+    let (int, rest) = body.split_at(
+        body.find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(body.len()),
+    );
+    let alnum = |c: char| c.is_ascii_alphanumeric();
+    let (radix, digits) = match rest.chars().next() {
+        None => (10, int),
+        Some('_') if is_grouped(body, |c| c.is_ascii_digit()) => (10, body),
+        Some(p @ ('x' | 'X' | 'o' | 'O' | 'b' | 'B'))
+            if int == "0" && is_grouped(&rest[1..], alnum) =>
+        {
+            match p.to_ascii_lowercase() {
+                'x' => (16, &rest[1..]),
+                'o' => (8, &rest[1..]),
+                _ => (2, &rest[1..]),
             }
         }
+        Some('r' | 'R') if int.len() <= 2 && is_grouped(&rest[1..], alnum) => {
+            (int.parse().unwrap(), &rest[1..])
+        }
+        Some('/') if rest.len() > 1 && rest[1..].chars().all(|c| c.is_ascii_digit()) => {
+            let denom: BigInt = rest[1..].parse().unwrap();
+            if denom.is_zero() {
+                return Some(error("divide by zero"));
+            }
+            let numer: BigInt = token[..token.len() - rest.len()].parse().unwrap();
+            return Some(Ok(ratio(BigRational::new(numer, denom))));
+        }
+        Some(_) if is_float_tail(rest) => return Some(Ok(Float(token.parse().unwrap()))),
+        _ => return None,
+    };
+    if !(2..=36).contains(&radix) {
+        return Some(error(&format!("radix out of range: {}", radix)));
+    }
+    let digits = format!("{}{}", sign, digits.replace('_', ""));
+    Some(
+        int_from_str(&digits, radix).ok_or_else(|| ErrString(format!("invalid number: {}", token))),
+    )
+}
+
+fn read_atom(rdr: &mut Reader) -> MalRet {
+    let (token, kind) = rdr.next_kind()?;
+    match kind {
+        Kind::Number => read_number(token).unwrap_or_else(|| Ok(Sym(token.to_string()))),
+        Kind::Str(true) => unescape_str(&token[1..token.len() - 1]).map(Str),
+        Kind::Regex(true) => regex(&regex_source(&token[2..token.len() - 1])),
+        Kind::Str(false) | Kind::Regex(false) => {
+            // only the last token can run off the end of the input
+            rdr.incomplete = rdr.pos == rdr.tokens.len();
+            error("expected '\"', got EOF")
+        }
+        Kind::Other => match token {
+            "nil" => Ok(Nil),
+            "false" => Ok(Bool(false)),
+            "true" => Ok(Bool(true)),
+            "##Inf" => Ok(Float(f64::INFINITY)),
+            "##-Inf" => Ok(Float(f64::NEG_INFINITY)),
+            "##NaN" => Ok(Float(f64::NAN)),
+            _ => {
+                if let Some(c) = token.strip_prefix('\\') {
+                    read_char(c)
+                } else if let Some(k) = token.strip_prefix(':') {
+                    Ok(keyword(k))
+                } else {
+                    Ok(Sym(token.to_string()))
+                }
+            }
+        },
     }
 }
 
 fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
     let mut seq: Vec<MalVal> = vec![];
-    rdr.next()?;
+    let pos = Rc::new(rdr.position());
+    let start = rdr.next()?;
+    if start == "#(" {
+        if rdr.in_fn {
+            let e = ErrString("nested #()s are not allowed".to_string());
+            return Err(error_at(e, (*pos).clone()));
+        }
+        rdr.in_fn = true;
+    }
     loop {
         let token = match rdr.peek() {
             Ok(t) => t,
-            Err(_) => return error(&format!("expected '{}', got EOF", end)),
+            Err(_) => {
+                let e = ErrString(format!("expected '{}', got EOF", end));
+                return Err(error_at(e, (*pos).clone()));
+            }
         };
         if token == end {
             break;
         }
-        seq.push(read_form(rdr)?)
+        if let Some(form) = read_next(rdr)? {
+            seq.push(form);
+        }
     }
     let _ = rdr.next();
-    match end {
-        ")" => Ok(list!(seq)),
-        "]" => Ok(vector!(seq)),
-        "}" => hash_map(seq),
-        _ => error("read_seq unknown end value"),
+    match start {
+        "(" => Ok(List(seq.into(), pos)),
+        "[" => Ok(Vector(seq.into(), pos)),
+        "{" => _assoc(MalMap::default(), pos.clone(), seq).map_err(|e| error_at(e, (*pos).clone())),
+        "#{" => hash_set(seq),
+        "#(" => {
+            rdr.in_fn = false;
+            Ok(anon_fn(seq, pos))
+        }
+        _ => error("read_seq unknown start value"),
+    }
+}
+
+// #(...) reads as (fn* [%1 ... %n & %&] (...)), where n is the highest
+// numbered parameter used and % is short for %1
+fn anon_fn(body: Vec<MalVal>, pos: Rc<MalVal>) -> MalVal {
+    let (mut max, mut rest) = (0, false);
+    let body = anon_fn_args(&List(body.into(), pos.clone()), &mut max, &mut rest);
+    let mut params: Vec<MalVal> = (1..=max).map(|n| Sym(format!("%{}", n))).collect();
+    if rest {
+        params.push(Sym("&".to_string()));
+        params.push(Sym("%&".to_string()));
+    }
+    List(
+        vec![Sym("fn*".to_string()), vector!(params), body].into(),
+        pos,
+    )
+}
+
+fn anon_fn_args(form: &MalVal, max: &mut usize, rest: &mut bool) -> MalVal {
+    match form {
+        Sym(s) if s == "%&" => {
+            *rest = true;
+            form.clone()
+        }
+        Sym(s) => match s.strip_prefix('%') {
+            Some(n) if n.is_empty() || n.bytes().all(|b| b.is_ascii_digit()) => {
+                let n = n.parse().unwrap_or(1);
+                if n == 0 {
+                    return form.clone();
+                }
+                *max = (*max).max(n);
+                Sym(format!("%{}", n))
+            }
+            _ => form.clone(),
+        },
+        List(l, meta) => List(
+            l.iter()
+                .map(|f| anon_fn_args(f, max, rest))
+                .collect::<MalSeq>(),
+            meta.clone(),
+        ),
+        Vector(l, meta) => Vector(
+            l.iter()
+                .map(|f| anon_fn_args(f, max, rest))
+                .collect::<MalSeq>(),
+            meta.clone(),
+        ),
+        Hash(hm, meta) => Hash(
+            hm.iter()
+                .map(|(k, v)| (anon_fn_args(k, max, rest), anon_fn_args(v, max, rest)))
+                .collect(),
+            meta.clone(),
+        ),
+        Set(hs, meta) => Set(
+            hs.iter().map(|f| anon_fn_args(f, max, rest)).collect(),
+            meta.clone(),
+        ),
+        _ => form.clone(),
+    }
+}
+
+// #tag form reads as the reader function for tag in *data-readers*
+// applied to form, falling back on the built in #inst and #uuid
+fn read_tagged(rdr: &mut Reader) -> MalRet {
+    let tag = &rdr.next()?[1..];
+    let form = read_form(rdr)?;
+    if rdr.suppress {
+        return Ok(Tagged(Rc::from(tag), Rc::new(form)));
+    }
+    if let Some(Hash(readers, _)) = rdr.lookup("*data-readers*") {
+        if let Some(f) = readers.get(&Sym(tag.to_string())) {
+            return f.apply(vec![form]);
+        }
+    }
+    match tag {
+        "inst" => read_inst(form),
+        "uuid" => read_uuid(form),
+        _ => error(&format!("no reader function for tag {}", tag)),
+    }
+}
+
+// Insts are kept as a timestamp in UTC, the way they print
+fn read_inst(form: MalVal) -> MalRet {
+    match form {
+        Str(s) => Ok(Tagged(
+            Rc::from("inst"),
+            Rc::new(Str(format_inst(inst_ms(&s)?))),
+        )),
+        _ => error("#inst requires a string"),
+    }
+}
+
+fn read_uuid(form: MalVal) -> MalRet {
+    lazy_static! {
+        static ref UUID_RE: Regex =
+            Regex::new(r"^[0-9a-fA-F]{8}-(?:[0-9a-fA-F]{4}-){3}[0-9a-fA-F]{12}$").unwrap();
+    }
+    match form {
+        Str(ref s) if UUID_RE.is_match(s) => {
+            Ok(Tagged(Rc::from("uuid"), Rc::new(Str(s.to_lowercase()))))
+        }
+        _ => error("#uuid requires a string of hex digits grouped 8-4-4-4-12"),
+    }
+}
+
+/// Milliseconds since the epoch of an RFC 3339 timestamp, as taken by
+/// #inst. Everything after the year may be left off.
+pub fn inst_ms(s: &str) -> Result<i64, MalErr> {
+    lazy_static! {
+        static ref INST_RE: Regex = Regex::new(
+            r"^(\d{4})(?:-(\d{2})(?:-(\d{2})(?:T(\d{2})(?::(\d{2})(?::(\d{2})(?:\.(\d+))?)?)?)?)?)?(Z|[-+]\d{2}:\d{2})?$"
+        )
+        .unwrap();
+    }
+    let invalid = || ErrString(format!("invalid #inst: {}", s));
+    let caps = INST_RE.captures(s).ok_or_else(invalid)?;
+    let field =
+        |i: usize, default: i64| caps.get(i).map_or(default, |m| m.as_str().parse().unwrap());
+    let (y, mo, d) = (field(1, 0), field(2, 1), field(3, 1));
+    let (h, mi, sec) = (field(4, 0), field(5, 0), field(6, 0));
+    if !(1..=12).contains(&mo) || d < 1 || d > days_in_month(y, mo) || h > 23 || mi > 59 || sec > 60
+    {
+        return Err(invalid());
+    }
+    // the first three digits of the fraction are the milliseconds
+    let ms = caps.get(7).map_or(0, |m| {
+        let digits: String = m.as_str().chars().chain("00".chars()).take(3).collect();
+        digits.parse().unwrap()
+    });
+    let offset = match caps.get(8).map(|m| m.as_str()) {
+        None | Some("Z") => 0,
+        Some(o) => {
+            let mins = o[1..3].parse::<i64>().unwrap() * 60 + o[4..6].parse::<i64>().unwrap();
+            if o.starts_with('-') {
+                -mins
+            } else {
+                mins
+            }
+        }
+    };
+    let mins = (days_from_civil(y, mo, d) * 24 + h) * 60 + mi - offset;
+    Ok(mins * 60_000 + sec * 1000 + ms)
+}
+
+fn format_inst(ms: i64) -> String {
+    let (days, ms) = (ms.div_euclid(86_400_000), ms.rem_euclid(86_400_000));
+    let (y, m, d) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}-00:00",
+        y,
+        m,
+        d,
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+fn days_in_month(y: i64, m: i64) -> i64 {
+    match m {
+        2 if y % 4 == 0 && (y % 100 != 0 || y % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 in the proleptic Gregorian calendar, counted in
+// 400 year eras from a year that starts in March
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + if m <= 2 { 1 } else { 0 }, m, d)
+}
+
+// #?(:rust a :default b) reads as the form for the first key that is
+// :default or *host-language* as a keyword, or as nothing if none is
+fn read_cond(rdr: &mut Reader) -> Result<Option<MalVal>, MalErr> {
+    let pos = rdr.position_at(rdr.pos - 1);
+    if rdr.peek()? != "(" {
+        let e = ErrString("reader conditional body must be a list".to_string());
+        return Err(error_at(e, pos));
+    }
+    let _ = rdr.next();
+    let host = match rdr.lookup("*host-language*") {
+        Some(Str(s)) => keyword(&s),
+        _ => keyword("rust"),
+    };
+    let mut found = None;
+    loop {
+        let key = match rdr.peek() {
+            Ok(")") => break,
+            Ok(_) => read_form(rdr)?,
+            Err(_) => {
+                let e = ErrString("expected ')', got EOF".to_string());
+                return Err(error_at(e, pos));
+            }
+        };
+        if matches!(rdr.peek(), Ok(")")) {
+            let e = ErrString("reader conditional requires an even number of forms".to_string());
+            return Err(error_at(e, pos));
+        }
+        let picked = found.is_none() && (key == host || key == keyword("default"));
+        // branches that are not picked are read but their tags are left
+        // unresolved, as they may be for another host
+        let suppress = rdr.suppress;
+        rdr.suppress = suppress || !picked;
+        let form = read_form(rdr);
+        rdr.suppress = suppress;
+        if picked {
+            found = Some(form?);
+        } else {
+            form?;
+        }
+    }
+    let _ = rdr.next();
+    Ok(found)
+}
+
+// The next form, or None for #_ and the form it discards, and for reader
+// conditionals with no branch for this host
+fn read_next(rdr: &mut Reader) -> Result<Option<MalVal>, MalErr> {
+    match rdr.peek()? {
+        "#_" => {
+            let _ = rdr.next();
+            read_form(rdr)?;
+            Ok(None)
+        }
+        "#?" => {
+            let _ = rdr.next();
+            read_cond(rdr)
+        }
+        _ => read_syntax(rdr).map(Some),
     }
 }
 
 fn read_form(rdr: &mut Reader) -> MalRet {
+    loop {
+        if let Some(form) = read_next(rdr)? {
+            return Ok(form);
+        }
+    }
+}
+
+fn read_syntax(rdr: &mut Reader) -> MalRet {
+    let start = rdr.pos;
     let token = rdr.peek()?;
-    match &token[..] {
+    match token {
         "'" => {
             let _ = rdr.next();
             Ok(list![Sym("quote".to_string()), read_form(rdr)?])
@@ -133,24 +755,309 @@ fn read_form(rdr: &mut Reader) -> MalRet {
             let _ = rdr.next();
             Ok(list![Sym("deref".to_string()), read_form(rdr)?])
         }
-        ")" => error("unexpected ')'"),
+        "#'" => {
+            let _ = rdr.next();
+            Ok(list![Sym("var".to_string()), read_form(rdr)?])
+        }
+        ")" | "]" | "}" => Err(error_at(
+            ErrString(format!("unexpected '{}'", token)),
+            rdr.position(),
+        )),
         "(" => read_seq(rdr, ")"),
-        "]" => error("unexpected ']'"),
         "[" => read_seq(rdr, "]"),
-        "}" => error("unexpected '}'"),
         "{" => read_seq(rdr, "}"),
-        _ => read_atom(rdr),
+        "#{" => read_seq(rdr, "}"),
+        "#(" => read_seq(rdr, ")"),
+        _ if token.starts_with('#') && token[1..].starts_with(char::is_alphabetic) => {
+            read_tagged(rdr).map_err(|e| error_at(e, rdr.position_at(start)))
+        }
+        _ => read_atom(rdr).map_err(|e| error_at(e, rdr.position_at(start))),
     }
 }
 
 pub fn read_str(str: String) -> MalRet {
-    let tokens = tokenize(&str);
-    //println!("tokens: {:?}", tokens);
-    if tokens.len() == 0 {
-        return error("no input");
-    }
-    read_form(&mut Reader {
-        pos: 0,
-        tokens: tokens,
-    })
+    read_str_in(&str, None)
+}
+
+// As read_str, with *host-language* and *data-readers* taken from env
+pub fn read_str_in(str: &str, env: Option<&Env>) -> MalRet {
+    let mut rdr = Reader::new(tokenize(str, 1, 1), None, env);
+    //println!("tokens: {:?}", rdr.tokens);
+    while rdr.pos < rdr.tokens.len() {
+        if let Some(form) = read_next(&mut rdr)? {
+            return Ok(form);
+        }
+    }
+    error("no input")
+}
+
+// Reads every form in str; positions record file as their :file
+pub fn read_all(str: &str, file: Option<&str>, env: Option<&Env>) -> Result<Vec<MalVal>, MalErr> {
+    let mut rdr = Reader::new(tokenize(str, 1, 1), file.map(Rc::from), env);
+    let mut forms = vec![];
+    while rdr.pos < rdr.tokens.len() {
+        if let Some(form) = read_next(&mut rdr)? {
+            forms.push(form);
+        }
+    }
+    Ok(forms)
+}
+
+/// What the start of some input that may not have fully arrived yet holds
+#[derive(Debug)]
+pub enum Partial {
+    /// A complete form, and the number of bytes of input it took up
+    Form(MalVal, usize),
+    /// The input stops part way through a form; reading it as it stands
+    /// gives the error
+    Incomplete(MalErr),
+    /// Only whitespace, comments and forms that read as nothing
+    Empty,
+}
+
+/// Reads the first form of src, telling apart input that is wrong from
+/// input that is unfinished, as a REPL needs to before asking for more
+pub fn read_partial(src: &str) -> Result<Partial, MalErr> {
+    read_partial_in(src, None)
+}
+
+/// Like read_partial, with *host-language* and *data-readers* from env
+pub fn read_partial_in(src: &str, env: Option<&Env>) -> Result<Partial, MalErr> {
+    read_partial_at(src, None, env, 1, 1)
+}
+
+fn incomplete(e: MalErr) -> MalErr {
+    match e {
+        ErrAt(e, pos) => ErrAt(Box::new(incomplete(*e)), pos),
+        ErrString(s) => ErrString(format!("incomplete input, {}", s)),
+        e => e,
+    }
+}
+
+fn read_partial_at(
+    src: &str,
+    file: Option<Rc<str>>,
+    env: Option<&Env>,
+    line: usize,
+    column: usize,
+) -> Result<Partial, MalErr> {
+    let mut rdr = Reader::new(tokenize(src, line, column), file, env);
+    while rdr.pos < rdr.tokens.len() {
+        match read_next(&mut rdr) {
+            Ok(Some(form)) => return Ok(Partial::Form(form, rdr.offset())),
+            Ok(None) => {}
+            Err(e) if rdr.incomplete => return Ok(Partial::Incomplete(incomplete(e))),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Partial::Empty)
+}
+
+/// Reads forms one at a time from a stream, pulling in a line at a time
+/// until the next form is complete. Input is lexed once, as it arrives,
+/// and the tokens are kept along with how many have been read, so each
+/// form is read from just its own tokens however much input is buffered.
+/// A read error skips the rest of the input buffered so far, and input
+/// that ends part way through a form gives an "incomplete input" error.
+///
+/// ```
+/// use mal::reader::StreamReader;
+///
+/// let input = "(+ 1\n   2) :a ; done\n[\"(\n\"]\n(list".as_bytes();
+/// let mut forms = StreamReader::new(input, None, None);
+/// assert_eq!(forms.next().unwrap().unwrap().pr_str(true), "(+ 1 2)");
+/// assert_eq!(forms.next().unwrap().unwrap().pr_str(true), ":a");
+/// assert_eq!(forms.next().unwrap().unwrap().pr_str(true), "[\"(\\n\"]");
+/// assert!(forms.next().unwrap().is_err());
+/// assert!(forms.next().is_none());
+/// ```
+pub struct StreamReader<R> {
+    input: BufReader<R>,
+    buf: String,
+    file: Option<Rc<str>>,
+    env: Option<Env>,
+    eof: bool,
+    // how much of buf has been lexed, and the line and column there
+    scanned: usize,
+    line: usize,
+    column: usize,
+    // the tokens lexed so far, and how many of them have been read
+    tokens: Vec<Span>,
+    cursor: usize,
+    // how far past cursor the brackets have been matched up, and how
+    // deeply they nest there
+    end: usize,
+    depth: isize,
+}
+
+// A token kept by where it is in the buffer rather than borrowing it, so
+// that more input can be added to the buffer while it is kept
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    start: usize,
+    end: usize,
+    kind: Kind,
+    line: usize,
+    column: usize,
+}
+
+impl Span {
+    fn token<'a>(&self, src: &'a str) -> Token<'a> {
+        Token {
+            text: &src[self.start..self.end],
+            kind: self.kind,
+            offset: self.start,
+            line: self.line,
+            column: self.column,
+        }
+    }
+}
+
+impl<R: Read> StreamReader<R> {
+    pub fn new(input: R, file: Option<&str>, env: Option<&Env>) -> StreamReader<R> {
+        StreamReader {
+            input: BufReader::new(input),
+            buf: String::new(),
+            file: file.map(Rc::from),
+            env: env.cloned(),
+            eof: false,
+            scanned: 0,
+            line: 1,
+            column: 1,
+            tokens: vec![],
+            cursor: 0,
+            end: 0,
+            depth: 0,
+        }
+    }
+
+    // Lexes what has been added to buf since the last call. A string that
+    // runs to the end of buf may be closed by the next line, so unless the
+    // input has ended it is lexed again once that is in.
+    fn scan(&mut self) {
+        let mut lexer = Lexer {
+            src: &self.buf,
+            offset: self.scanned,
+            line: self.line,
+            column: self.column,
+        };
+        while let Some(t) = lexer.next_token() {
+            if let Kind::Str(false) | Kind::Regex(false) = t.kind {
+                if lexer.offset == self.buf.len() && !self.eof {
+                    return;
+                }
+            }
+            self.tokens.push(Span {
+                start: t.offset,
+                end: lexer.offset,
+                kind: t.kind,
+                line: t.line,
+                column: t.column,
+            });
+            self.scanned = lexer.offset;
+            self.line = lexer.line;
+            self.column = lexer.column;
+        }
+        self.scanned = lexer.offset;
+        self.line = lexer.line;
+        self.column = lexer.column;
+    }
+
+    // Reads the next form from the tokens after cursor. Each try takes in
+    // tokens up to where the brackets next balance, and only takes in more
+    // if those turn out not to be enough, as for 'a or #_ a b. None means
+    // the tokens run out first.
+    fn read_form(&mut self) -> Option<MalRet> {
+        loop {
+            let mut balanced = false;
+            while self.end < self.tokens.len() && !balanced {
+                let t = self.tokens[self.end];
+                match &self.buf[t.start..t.end] {
+                    "(" | "[" | "{" | "#{" | "#(" => self.depth += 1,
+                    ")" | "]" | "}" => self.depth -= 1,
+                    _ => {}
+                }
+                self.end += 1;
+                balanced = self.depth <= 0;
+            }
+            if self.cursor == self.tokens.len() || !balanced && !self.eof {
+                return None;
+            }
+            let tokens = self.tokens[self.cursor..self.end]
+                .iter()
+                .map(|t| t.token(&self.buf))
+                .collect();
+            let mut rdr = Reader::new(tokens, self.file.clone(), self.env.as_ref());
+            let read = read_next(&mut rdr);
+            let (pos, more) = (rdr.pos, rdr.incomplete);
+            match read {
+                Ok(form) => {
+                    self.cursor += pos;
+                    self.end = self.cursor;
+                    self.depth = 0;
+                    if let Some(form) = form {
+                        return Some(Ok(form));
+                    }
+                }
+                Err(_) if more && self.end < self.tokens.len() => {}
+                Err(_) if more && !self.eof => return None,
+                Err(e) => {
+                    self.reset();
+                    return Some(Err(if more { incomplete(e) } else { e }));
+                }
+            }
+        }
+    }
+
+    // Once every token has been read, drops the text they came from
+    fn compact(&mut self) {
+        if self.cursor == self.tokens.len() {
+            self.buf.drain(..self.scanned);
+            self.scanned = 0;
+            self.tokens.clear();
+            self.cursor = 0;
+            self.end = 0;
+            self.depth = 0;
+        }
+    }
+
+    // drops buf and everything known about it
+    fn reset(&mut self) {
+        for c in self.buf[self.scanned..].chars() {
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+        self.scanned = self.buf.len();
+        self.cursor = self.tokens.len();
+        self.compact();
+    }
+}
+
+impl<R: Read> Iterator for StreamReader<R> {
+    type Item = MalRet;
+
+    fn next(&mut self) -> Option<MalRet> {
+        loop {
+            self.scan();
+            if let Some(read) = self.read_form() {
+                return Some(read);
+            }
+            if self.eof {
+                return None;
+            }
+            self.compact();
+            match self.input.read_line(&mut self.buf) {
+                Ok(0) => self.eof = true,
+                Ok(_) => {}
+                Err(e) => {
+                    self.eof = true;
+                    return Some(error(&format!("read error: {}", e)));
+                }
+            }
+        }
+    }
 }
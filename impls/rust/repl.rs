@@ -0,0 +1,151 @@
+//! The read-eval-print loop the step binaries run: a file given on the
+//! command line is loaded with the rest of the arguments as *ARGV*, and
+//! otherwise forms are read from standard input.
+
+use std::io::IsTerminal;
+
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+
+use crate::core::print_limits;
+use crate::reader::{self, Partial, StreamReader};
+use crate::types::MalVal::Str;
+use crate::types::{format_error, MalErr, MalSeq, MalVal};
+use crate::Interpreter;
+
+/// An interpreter, and how to show the results of what it evaluates
+pub struct Repl {
+    mal: Interpreter,
+    banner: bool,
+    pretty: bool,
+}
+
+impl Repl {
+    /// The full REPL: a banner naming the host language, and results
+    /// pretty printed to *print-right-margin*
+    pub fn new(mal: Interpreter) -> Repl {
+        Repl {
+            mal,
+            banner: true,
+            pretty: true,
+        }
+    }
+
+    /// Each result on a line of its own, as the tests of the steps before
+    /// stepA expect, and no banner
+    pub fn plain(mal: Interpreter) -> Repl {
+        Repl {
+            mal,
+            banner: false,
+            pretty: false,
+        }
+    }
+
+    fn ep(&self, form: MalVal) -> Result<String, MalErr> {
+        if self.pretty {
+            return self.mal.ep(form);
+        }
+        let env = self.mal.env();
+        self.mal.eval(form)?.pr_limited(true, print_limits(env))
+    }
+
+    fn print(&self, form: MalVal) {
+        match self.ep(form) {
+            Ok(out) => println!("{}", out),
+            Err(e) => println!("Error: {}", format_error(e)),
+        }
+    }
+
+    /// Runs the file named by the first command line argument and exits,
+    /// or else reads forms from standard input until it ends
+    pub fn run(self) {
+        let mut args = std::env::args();
+        let arg1 = args.nth(1);
+        let argv = list!(args.map(Str).collect::<MalSeq>());
+        self.mal.define("*ARGV*", argv);
+
+        // Invoked with arguments
+        if let Some(f) = arg1 {
+            match self.mal.eval_file(&f) {
+                Ok(_) => std::process::exit(0),
+                Err(e) => {
+                    println!("Error: {}", format_error(e));
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        if self.banner {
+            let _ = self
+                .mal
+                .rep("(println (str \"Mal [\" *host-language* \"]\"))");
+        }
+        if !std::io::stdin().is_terminal() {
+            // piped in: read whole forms, whatever lines they span
+            let env = Some(self.mal.env());
+            for form in StreamReader::new(std::io::stdin().lock(), None, env) {
+                match form {
+                    Ok(form) => self.print(form),
+                    Err(e) => println!("Error: {}", format_error(e)),
+                }
+            }
+            return;
+        }
+        self.interact();
+    }
+
+    fn interact(&self) {
+        // `()` can be used when no completer is required
+        let mut rl = Editor::<()>::new();
+        if rl.load_history(".mal-history").is_err() {
+            eprintln!("No previous history.");
+        }
+
+        // lines of a form that is not finished yet
+        let mut input = String::new();
+        let env = Some(self.mal.env());
+        loop {
+            let prompt = if input.is_empty() { "user> " } else { "...> " };
+            let readline = rl.readline(prompt);
+            match readline {
+                Ok(line) => {
+                    rl.add_history_entry(&line);
+                    rl.save_history(".mal-history").unwrap();
+                    input.push_str(&line);
+                    input.push('\n');
+                    // run each complete form, keeping an unfinished one to
+                    // go on with on the next line
+                    loop {
+                        match reader::read_partial_in(&input, env) {
+                            Ok(Partial::Form(form, len)) => {
+                                input.drain(..len);
+                                self.print(form);
+                            }
+                            Ok(Partial::Incomplete(_)) => break,
+                            Ok(Partial::Empty) => {
+                                input.clear();
+                                break;
+                            }
+                            Err(e) => {
+                                println!("Error: {}", format_error(e));
+                                input.clear();
+                                break;
+                            }
+                        }
+                    }
+                }
+                Err(ReadlineError::Interrupted) => input.clear(),
+                Err(ReadlineError::Eof) => {
+                    if let Ok(Partial::Incomplete(e)) = reader::read_partial_in(&input, env) {
+                        println!("Error: {}", format_error(e));
+                    }
+                    break;
+                }
+                Err(err) => {
+                    println!("Error: {:?}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! The elements of a list or vector. Short ones, which most code is made
+//! of, are a plain shared slice; longer ones are a persistent RRB vector,
+//! so that cons, conj, rest and nth stay cheap however long they grow.
+
+use std::iter::FromIterator;
+use std::ops::Index;
+use std::rc::Rc;
+
+use crate::types::MalVal;
+
+// The most elements kept in a slice. Changing a slice copies it, which at
+// this length is no slower than changing an RRB vector, and it takes a
+// fraction of the memory of an RRB vector's first chunk
+const SMALL_LEN: usize = 32;
+
+#[derive(Clone)]
+pub struct MalSeq(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Small(Rc<[MalVal]>),
+    Big(Rc<im_rc::Vector<MalVal>>),
+}
+
+impl MalSeq {
+    pub fn new() -> MalSeq {
+        MalSeq(Repr::Small(Rc::new([])))
+    }
+
+    fn from_vector(v: im_rc::Vector<MalVal>) -> MalSeq {
+        if v.len() <= SMALL_LEN {
+            MalSeq(Repr::Small(v.into_iter().collect()))
+        } else {
+            MalSeq(Repr::Big(Rc::new(v)))
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self.0 {
+            Repr::Small(ref s) => s.len(),
+            Repr::Big(ref v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, i: usize) -> Option<&MalVal> {
+        match self.0 {
+            Repr::Small(ref s) => s.get(i),
+            Repr::Big(ref v) => v.get(i),
+        }
+    }
+
+    pub fn head(&self) -> Option<&MalVal> {
+        self.get(0)
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        match self.0 {
+            Repr::Small(ref s) => Iter::Small(s.iter()),
+            Repr::Big(ref v) => Iter::Big(v.iter()),
+        }
+    }
+
+    pub fn push_front(&mut self, x: MalVal) {
+        match self.0 {
+            Repr::Small(ref s) => {
+                *self = std::iter::once(x).chain(s.iter().cloned()).collect();
+            }
+            Repr::Big(ref mut v) => Rc::make_mut(v).push_front(x),
+        }
+    }
+
+    pub fn pop_front(&mut self) -> Option<MalVal> {
+        let first = self.head().cloned();
+        match self.0 {
+            Repr::Small(ref s) if !s.is_empty() => *self = s[1..].iter().cloned().collect(),
+            Repr::Small(_) => {}
+            Repr::Big(ref mut v) => {
+                Rc::make_mut(v).pop_front();
+                if v.len() <= SMALL_LEN {
+                    *self = v.iter().cloned().collect();
+                }
+            }
+        }
+        first
+    }
+
+    pub fn append(&mut self, other: MalSeq) {
+        if self.is_empty() {
+            *self = other;
+        } else if !other.is_empty() {
+            match (&mut self.0, other.0) {
+                (Repr::Big(ref mut v), Repr::Big(w)) => {
+                    Rc::make_mut(v).append(Rc::try_unwrap(w).unwrap_or_else(|w| (*w).clone()))
+                }
+                (_, w) => self.extend(MalSeq(w).iter().cloned()),
+            }
+        }
+    }
+
+    // The elements from n on, sharing structure with self when it is long
+    pub fn skip(&self, n: usize) -> MalSeq {
+        match self.0 {
+            Repr::Small(ref s) => s[n.min(s.len())..].iter().cloned().collect(),
+            Repr::Big(ref v) if n >= v.len() => MalSeq::new(),
+            Repr::Big(ref v) => MalSeq::from_vector(v.skip(n)),
+        }
+    }
+
+    // The first n elements, sharing structure with self when it is long
+    pub fn take(&self, n: usize) -> MalSeq {
+        match self.0 {
+            Repr::Small(ref s) => s[..n.min(s.len())].iter().cloned().collect(),
+            Repr::Big(ref v) if n >= v.len() => self.clone(),
+            Repr::Big(ref v) => MalSeq::from_vector(v.take(n)),
+        }
+    }
+}
+
+impl Default for MalSeq {
+    fn default() -> MalSeq {
+        MalSeq::new()
+    }
+}
+
+impl std::fmt::Debug for MalSeq {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl PartialEq for MalSeq {
+    fn eq(&self, other: &MalSeq) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl Eq for MalSeq {}
+
+impl Index<usize> for MalSeq {
+    type Output = MalVal;
+
+    fn index(&self, i: usize) -> &MalVal {
+        match self.0 {
+            Repr::Small(ref s) => &s[i],
+            Repr::Big(ref v) => &v[i],
+        }
+    }
+}
+
+impl From<Vec<MalVal>> for MalSeq {
+    fn from(v: Vec<MalVal>) -> MalSeq {
+        if v.len() <= SMALL_LEN {
+            MalSeq(Repr::Small(Rc::from(v)))
+        } else {
+            MalSeq(Repr::Big(Rc::new(im_rc::Vector::from(v))))
+        }
+    }
+}
+
+impl FromIterator<MalVal> for MalSeq {
+    fn from_iter<I: IntoIterator<Item = MalVal>>(iter: I) -> MalSeq {
+        MalSeq::from(iter.into_iter().collect::<Vec<_>>())
+    }
+}
+
+impl Extend<MalVal> for MalSeq {
+    fn extend<I: IntoIterator<Item = MalVal>>(&mut self, iter: I) {
+        match self.0 {
+            Repr::Small(ref s) => *self = s.iter().cloned().chain(iter).collect(),
+            Repr::Big(ref mut v) => Rc::make_mut(v).extend(iter),
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a MalSeq {
+    type Item = &'a MalVal;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+pub enum Iter<'a> {
+    Small(std::slice::Iter<'a, MalVal>),
+    Big(im_rc::vector::Iter<'a, MalVal>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a MalVal;
+
+    fn next(&mut self) -> Option<&'a MalVal> {
+        match self {
+            Iter::Small(it) => it.next(),
+            Iter::Big(it) => it.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            Iter::Small(it) => it.size_hint(),
+            Iter::Big(it) => it.size_hint(),
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for Iter<'a> {
+    fn next_back(&mut self) -> Option<&'a MalVal> {
+        match self {
+            Iter::Small(it) => it.next_back(),
+            Iter::Big(it) => it.next_back(),
+        }
+    }
+}
+
+impl<'a> ExactSizeIterator for Iter<'a> {}
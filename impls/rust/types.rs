@@ -1,64 +1,265 @@
 use std::cell::RefCell;
+use std::hash::Hasher;
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
+use fnv::{FnvHashSet, FnvHasher};
 use itertools::Itertools;
+use num_rational::BigRational;
+use num_traits::{One, ToPrimitive};
 
 use crate::env::{env_bind, Env};
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::MalErr::{ErrAt, ErrMalVal, ErrString};
+use crate::types::MalVal::{
+    Atom, BigInt, Bool, Char, Float, Func, Hash, Int, Keyword, LazySeq, List, MalFunc, Nil,
+    Position, Ratio, Regex, Set, Str, Sym, Tagged, Vector,
+};
 
 #[derive(Debug, Clone)]
 pub enum MalVal {
     Nil,
     Bool(bool),
     Int(i64),
-    //Float(f64),
+    BigInt(Rc<num_bigint::BigInt>),
+    Ratio(Rc<BigRational>),
+    Float(f64),
     Str(String),
+    Char(char),
     Sym(String),
-    List(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Vector(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Hash(Rc<FnvHashMap<String, MalVal>>, Rc<MalVal>),
-    Func(fn(MalArgs) -> MalRet, Rc<MalVal>),
-    MalFunc {
-        eval: fn(ast: MalVal, env: Env) -> MalRet,
-        ast: Rc<MalVal>,
-        env: Env,
-        params: Rc<MalVal>,
-        is_macro: bool,
-        meta: Rc<MalVal>,
-    },
+    Keyword(Rc<str>),
+    List(MalSeq, Rc<MalVal>),
+    Vector(MalSeq, Rc<MalVal>),
+    Hash(MalMap, Rc<MalVal>),
+    Set(MalSet, Rc<MalVal>),
+    Func(Rc<NativeFn>, Rc<MalVal>),
+    MalFunc(Rc<Closure>, Rc<MalVal>),
     Atom(Rc<RefCell<MalVal>>),
+    LazySeq(Rc<Lazy>),
+    Regex(Rc<regex::Regex>),
+    // a #tag literal that reads as the tagged form, such as #inst
+    Tagged(Rc<str>, Rc<MalVal>),
+    // where the reader found a collection, kept as its metadata
+    Position(Pos),
+}
+
+// A line and column, counted from 1, and the file if there was one. The
+// reader attaches one to every collection it reads, so it is kept small,
+// sharing the file name, and only made into a {:line :column :file} map
+// when meta asks for it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Pos {
+    pub line: u32,
+    pub column: u32,
+    pub file: Option<Rc<str>>,
+}
+
+impl Pos {
+    pub fn to_map(&self) -> MalVal {
+        let mut hm = MalMap::default();
+        hm.insert(keyword("line"), Int(self.line as i64));
+        hm.insert(keyword("column"), Int(self.column as i64));
+        if let Some(ref f) = self.file {
+            hm.insert(keyword("file"), Str(f.to_string()));
+        }
+        Hash(hm, Rc::new(Nil))
+    }
+}
+
+// A function defined in mal by fn*, along with the environment it closes
+// over. It is kept behind an Rc so that it does not make every MalVal as
+// big as itself
+pub struct Closure {
+    pub eval: fn(ast: MalVal, env: Env) -> MalRet,
+    pub ast: Rc<MalVal>,
+    pub env: Env,
+    pub params: Rc<MalVal>,
+    pub is_macro: bool,
+}
+
+impl std::fmt::Debug for Closure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Closure")
+            .field("ast", &self.ast)
+            .field("params", &self.params)
+            .field("is_macro", &self.is_macro)
+            .finish()
+    }
+}
+
+// A builtin implemented in Rust. The closure may capture state, which lets
+// host programs register functions that close over their own data.
+#[derive(Clone)]
+pub struct NativeFn {
+    pub name: Rc<str>,
+    pub arity: Arity,
+    pub f: Rc<dyn Fn(MalArgs) -> MalRet>,
+}
+
+impl std::fmt::Debug for NativeFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "NativeFn({}, {:?})", self.name, self.arity)
+    }
+}
+
+// The number of arguments a function accepts; max is None when variadic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Arity {
+    pub min: usize,
+    pub max: Option<usize>,
+}
+
+impl Arity {
+    pub fn exactly(n: usize) -> Arity {
+        Arity {
+            min: n,
+            max: Some(n),
+        }
+    }
+
+    pub fn between(min: usize, max: usize) -> Arity {
+        Arity {
+            min,
+            max: Some(max),
+        }
+    }
+
+    pub fn at_least(min: usize) -> Arity {
+        Arity { min, max: None }
+    }
+
+    pub fn accepts(&self, n: usize) -> bool {
+        n >= self.min && self.max.is_none_or(|max| n <= max)
+    }
+}
+
+impl std::fmt::Display for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{}", max),
+            Some(max) => write!(f, "{} to {}", self.min, max),
+            None => write!(f, "{} or more", self.min),
+        }
+    }
+}
+
+pub fn arity_error(name: &str, given: usize, arity: Arity) -> MalErr {
+    ErrString(format!(
+        "wrong number of args ({}) passed to {}, expected {}",
+        given, name, arity
+    ))
+}
+
+// A lazy sequence starts out as a thunk and caches the first step of the
+// sequence (first element and the rest, or None when empty) once realized
+pub enum LazyState {
+    Thunk(MalVal),
+    Native(Box<dyn FnOnce() -> MalRet>),
+    Realizing,
+    Realized(SeqStep),
+    Failed(MalErr),
+}
+
+impl std::fmt::Debug for LazyState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LazyState::Realized(step) => write!(f, "Realized({:?})", step),
+            LazyState::Failed(e) => write!(f, "Failed({:?})", e),
+            _ => write!(f, "Unrealized"),
+        }
+    }
 }
 
 #[derive(Debug)]
+pub struct Lazy {
+    pub state: RefCell<LazyState>,
+}
+
+// Dropping a long realized seq would otherwise recurse once per element
+impl Drop for Lazy {
+    fn drop(&mut self) {
+        let mut next = match std::mem::replace(self.state.get_mut(), LazyState::Realizing) {
+            LazyState::Realized(Some((_, rest))) => rest,
+            _ => return,
+        };
+        while let LazySeq(cell) = next {
+            let mut lazy = match Rc::try_unwrap(cell) {
+                Ok(lazy) => lazy,
+                Err(_) => return,
+            };
+            next = match std::mem::replace(lazy.state.get_mut(), LazyState::Realizing) {
+                LazyState::Realized(Some((_, rest))) => rest,
+                _ => return,
+            };
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum MalErr {
     ErrString(String),
     ErrMalVal(MalVal),
+    // Raised while evaluating the form read at a {:file :line :column}
+    // position; try*/catch* sees the wrapped error
+    ErrAt(Box<MalErr>, MalVal),
+}
+
+impl MalErr {
+    // The value bound by catch*
+    pub fn value(&self) -> MalVal {
+        match self {
+            ErrString(s) => Str(s.clone()),
+            ErrMalVal(mv) => mv.clone(),
+            ErrAt(e, _) => e.value(),
+        }
+    }
 }
 
 pub type MalArgs = Vec<MalVal>;
+pub use crate::map::{MalMap, MalSet};
+pub use crate::seq::MalSeq;
 pub type MalRet = Result<MalVal, MalErr>;
+pub type SeqStep = Option<(MalVal, MalVal)>;
+
+// All but the first element. Popping the front of a long seq copies only
+// the chunk it was in, where MalSeq::skip copies it and then splits it
+pub fn seq_rest(l: &MalSeq) -> MalSeq {
+    let mut rest = l.clone();
+    rest.pop_front();
+    rest
+}
 
 // type utility macros
 
+#[macro_export]
 macro_rules! list {
   ($seq:expr) => {{
-    List(Rc::new($seq),Rc::new(Nil))
+    $crate::types::MalVal::List(
+      $crate::types::MalSeq::from($seq),
+      ::std::rc::Rc::new($crate::types::MalVal::Nil),
+    )
   }};
   [$($args:expr),*] => {{
-    let v: Vec<MalVal> = vec![$($args),*];
-    List(Rc::new(v),Rc::new(Nil))
+    let v: ::std::vec::Vec<$crate::types::MalVal> = ::std::vec![$($args),*];
+    $crate::types::MalVal::List(
+      $crate::types::MalSeq::from(v),
+      ::std::rc::Rc::new($crate::types::MalVal::Nil),
+    )
   }}
 }
 
+#[macro_export]
 macro_rules! vector {
   ($seq:expr) => {{
-    Vector(Rc::new($seq),Rc::new(Nil))
+    $crate::types::MalVal::Vector(
+      $crate::types::MalSeq::from($seq),
+      ::std::rc::Rc::new($crate::types::MalVal::Nil),
+    )
   }};
   [$($args:expr),*] => {{
-    let v: Vec<MalVal> = vec![$($args),*];
-    Vector(Rc::new(v),Rc::new(Nil))
+    let v: ::std::vec::Vec<$crate::types::MalVal> = ::std::vec![$($args),*];
+    $crate::types::MalVal::Vector(
+      $crate::types::MalSeq::from(v),
+      ::std::rc::Rc::new($crate::types::MalVal::Nil),
+    )
   }}
 }
 
@@ -68,30 +269,199 @@ pub fn error(s: &str) -> MalRet {
     Err(ErrString(s.to_string()))
 }
 
+// A built-in error that catch* sees as {:type kind :message s}, so that a
+// handler can tell an arithmetic error from a wrong type and the like
+pub fn typed_error(kind: &str, s: &str) -> MalErr {
+    let mut hm = MalMap::default();
+    hm.insert(keyword("type"), keyword(kind));
+    hm.insert(keyword("message"), Str(s.to_string()));
+    ErrMalVal(Hash(hm, Rc::new(Nil)))
+}
+
+// The message of a typed error, shown in place of the whole map
+fn typed_message(mv: &MalVal) -> Option<String> {
+    match mv {
+        Hash(hm, _) if hm.get(&keyword("type")).is_some_and(|t| t.keyword_q()) => {
+            match hm.get(&keyword("message")) {
+                Some(Str(s)) => Some(s.clone()),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
 pub fn format_error(e: MalErr) -> String {
     match e {
         ErrString(s) => s.clone(),
-        ErrMalVal(mv) => mv.pr_str(true),
+        ErrMalVal(mv) => typed_message(&mv).unwrap_or_else(|| mv.pr_str(true)),
+        ErrAt(e, pos) => format!("{} (at {})", format_error(*e), format_position(&pos)),
+    }
+}
+
+// Where a collection was read from, or its metadata if that is a map
+// with a :line, as macros may give the forms they build. eval asks for it
+// on every step, so the key is interned only once
+pub fn position(mv: &MalVal) -> Option<MalVal> {
+    match mv {
+        List(_, meta) | Vector(_, meta) | Hash(_, meta) => match **meta {
+            Position(_) => Some((**meta).clone()),
+            Hash(ref hm, _) if LINE.with(|line| hm.contains_key(line)) => Some((**meta).clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+pub fn format_position(pos: &MalVal) -> String {
+    if let Position(p) = pos {
+        return match p.file {
+            Some(ref f) => format!("{}:{}:{}", f, p.line, p.column),
+            None => format!("{}:{}", p.line, p.column),
+        };
+    }
+    let get = |k: &str| match pos {
+        Hash(hm, _) => hm.get(&keyword(k)).map(|v| v.pr_str(false)),
+        _ => None,
+    };
+    let line_col = format!(
+        "{}:{}",
+        get("line").unwrap_or_default(),
+        get("column").unwrap_or_default()
+    );
+    match get("file") {
+        Some(file) => format!("{}:{}", file, line_col),
+        None => line_col,
+    }
+}
+
+// Integers that fit in an i64 are always represented as Int, so BigInt
+// only ever holds values outside of that range
+pub fn big_int(b: num_bigint::BigInt) -> MalVal {
+    match b.to_i64() {
+        Some(i) => Int(i),
+        None => BigInt(Rc::new(b)),
+    }
+}
+
+// An optional sign followed by digits in radix, which must be from 2 to 36
+pub fn int_from_str(s: &str, radix: u32) -> Option<MalVal> {
+    let digits = s.strip_prefix(['+', '-']).unwrap_or(s);
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    match i64::from_str_radix(s, radix) {
+        Ok(i) => Some(Int(i)),
+        Err(_) => num_bigint::BigInt::parse_bytes(s.as_bytes(), radix).map(big_int),
+    }
+}
+
+// BigRational keeps itself in lowest terms; whole results become integers
+pub fn ratio(r: BigRational) -> MalVal {
+    if r.denom().is_one() {
+        big_int(r.to_integer())
+    } else {
+        Ratio(Rc::new(r))
     }
 }
 
+thread_local! {
+    static KEYWORDS: RefCell<FnvHashSet<Rc<str>>> = RefCell::new(FnvHashSet::default());
+    static LINE: MalVal = keyword("line");
+}
+
+// Keywords are interned, so two keywords are equal exactly when they
+// share the same allocation
+pub fn keyword(name: &str) -> MalVal {
+    KEYWORDS.with(|kws| {
+        let mut kws = kws.borrow_mut();
+        match kws.get(name) {
+            Some(k) => Keyword(k.clone()),
+            None => {
+                let k: Rc<str> = Rc::from(name);
+                kws.insert(k.clone());
+                Keyword(k)
+            }
+        }
+    })
+}
+
+// f is a mal function of no arguments returning a seq (or nil)
+pub fn lazy_seq(f: MalVal) -> MalVal {
+    lazy(LazyState::Thunk(f))
+}
+
+pub fn lazy_native<F: FnOnce() -> MalRet + 'static>(f: F) -> MalVal {
+    lazy(LazyState::Native(Box::new(f)))
+}
+
+pub fn lazy_cons(first: MalVal, rest: MalVal) -> MalVal {
+    lazy(LazyState::Realized(Some((first, rest))))
+}
+
+fn lazy(state: LazyState) -> MalVal {
+    LazySeq(Rc::new(Lazy {
+        state: RefCell::new(state),
+    }))
+}
+
+// Thunks that return another unrealized lazy seq are followed in a loop
+// rather than recursively, and every cell along the way caches the result
+fn realize_lazy(cell: &Rc<Lazy>) -> Result<SeqStep, MalErr> {
+    let mut chain = vec![cell.clone()];
+    let res = loop {
+        let cur = chain[chain.len() - 1].clone();
+        let state = std::mem::replace(&mut *cur.state.borrow_mut(), LazyState::Realizing);
+        let v = match state {
+            LazyState::Realized(step) => break Ok(step),
+            LazyState::Failed(e) => break Err(e),
+            LazyState::Realizing => {
+                break Err(ErrString("lazy seq depends on its own value".to_string()))
+            }
+            LazyState::Thunk(f) => f.apply(vec![]),
+            LazyState::Native(f) => f(),
+        };
+        match v {
+            Ok(LazySeq(next)) => chain.push(next),
+            Ok(v) => break v.seq_step(),
+            Err(e) => break Err(e),
+        }
+    };
+    for c in chain {
+        *c.state.borrow_mut() = match res {
+            Ok(ref step) => LazyState::Realized(step.clone()),
+            Err(ref e) => LazyState::Failed(e.clone()),
+        };
+    }
+    res
+}
+
 pub fn atom(mv: &MalVal) -> MalVal {
     Atom(Rc::new(RefCell::new(mv.clone())))
 }
 
+pub fn regex(pattern: &str) -> MalRet {
+    match regex::Regex::new(pattern) {
+        Ok(re) => Ok(Regex(Rc::new(re))),
+        Err(e) => error(&format!("invalid regex: {}", e)),
+    }
+}
+
 impl MalVal {
     pub fn keyword(&self) -> MalRet {
         match self {
-            Str(s) if s.starts_with("\u{29e}") => Ok(Str(s.to_string())),
-            Str(s) => Ok(Str(format!("\u{29e}{}", s))),
+            Keyword(_) => Ok(self.clone()),
+            Str(s) => Ok(keyword(s)),
             _ => error("invalid type for keyword"),
         }
     }
 
     pub fn empty_q(&self) -> MalRet {
         match self {
-            List(l, _) | Vector(l, _) => Ok(Bool(l.len() == 0)),
+            List(l, _) | Vector(l, _) => Ok(Bool(l.is_empty())),
+            Set(s, _) => Ok(Bool(s.is_empty())),
             Nil => Ok(Bool(true)),
+            LazySeq(_) => Ok(Bool(self.seq_step()?.is_none())),
             _ => error("invalid type for empty?"),
         }
     }
@@ -99,35 +469,78 @@ impl MalVal {
     pub fn count(&self) -> MalRet {
         match self {
             List(l, _) | Vector(l, _) => Ok(Int(l.len() as i64)),
+            Set(s, _) => Ok(Int(s.len() as i64)),
             Nil => Ok(Int(0)),
+            LazySeq(_) => Ok(Int(self.seq_vec()?.len() as i64)),
             _ => error("invalid type for count"),
         }
     }
 
+    pub fn sequential_q(&self) -> bool {
+        matches!(self, List(_, _) | Vector(_, _) | LazySeq(_))
+    }
+
+    // Realizes a single step of a sequence: its first element and the rest
+    pub fn seq_step(&self) -> Result<SeqStep, MalErr> {
+        match self {
+            List(l, _) | Vector(l, _) => Ok(l.head().map(|h| (h.clone(), list!(seq_rest(l))))),
+            Nil => Ok(None),
+            LazySeq(cell) => realize_lazy(cell),
+            _ => Err(ErrString("expected a sequence".to_string())),
+        }
+    }
+
+    // Realizes a whole sequence into its elements
+    pub fn seq_vec(&self) -> Result<MalArgs, MalErr> {
+        let mut res = vec![];
+        let mut cur = self.clone();
+        loop {
+            match cur {
+                List(l, _) | Vector(l, _) => {
+                    res.extend(l.iter().cloned());
+                    return Ok(res);
+                }
+                Nil => return Ok(res),
+                LazySeq(_) => match cur.seq_step()? {
+                    Some((first, rest)) => {
+                        res.push(first);
+                        cur = rest;
+                    }
+                    None => return Ok(res),
+                },
+                _ => return Err(ErrString("expected a sequence".to_string())),
+            }
+        }
+    }
+
     pub fn apply(&self, args: MalArgs) -> MalRet {
         match *self {
-            Func(f, _) => f(args),
-            MalFunc {
-                eval,
-                ref ast,
-                ref env,
-                ref params,
-                ..
-            } => {
-                let a = &**ast;
-                let p = &**params;
-                let fn_env = env_bind(Some(env.clone()), p.clone(), args)?;
-                Ok(eval(a.clone(), fn_env)?)
+            Func(ref nf, _) if !nf.arity.accepts(args.len()) => {
+                let name = if nf.name.is_empty() { "fn" } else { &nf.name };
+                Err(arity_error(name, args.len(), nf.arity))
             }
+            Func(ref nf, _) => (nf.f)(args),
+            MalFunc(ref c, _) => {
+                let fn_env = env_bind(Some(c.env.clone()), (*c.params).clone(), args)?;
+                Ok((c.eval)((*c.ast).clone(), fn_env)?)
+            }
+            // (:k m) and (:k m default) look the keyword up in m
+            Keyword(_) if !Arity::between(1, 2).accepts(args.len()) => Err(arity_error(
+                &self.pr_str(true),
+                args.len(),
+                Arity::between(1, 2),
+            )),
+            Keyword(_) => match args.first() {
+                Some(Hash(hm, _)) => Ok(hm.get(self).or(args.get(1)).cloned().unwrap_or(Nil)),
+                Some(Set(hs, _)) if hs.contains(self) => Ok(self.clone()),
+                _ => Ok(args.get(1).cloned().unwrap_or(Nil)),
+            },
             _ => error("attempt to call non-function"),
         }
     }
 
     pub fn keyword_q(&self) -> bool {
-        match self {
-            Str(s) if s.starts_with("\u{29e}") => true,
-            _ => false,
-        }
+        matches!(self, Keyword(_))
     }
 
     pub fn deref(&self) -> MalRet {
@@ -162,9 +575,11 @@ impl MalVal {
 
     pub fn get_meta(&self) -> MalRet {
         match self {
-            List(_, meta) | Vector(_, meta) | Hash(_, meta) => Ok((&**meta).clone()),
-            Func(_, meta) => Ok((&**meta).clone()),
-            MalFunc { meta, .. } => Ok((&**meta).clone()),
+            List(_, meta) | Vector(_, meta) | Hash(_, meta) | Set(_, meta) => match **meta {
+                Position(ref p) => Ok(p.to_map()),
+                ref m => Ok(m.clone()),
+            },
+            Func(_, meta) | MalFunc(_, meta) => Ok((**meta).clone()),
             _ => error("meta not supported by type"),
         }
     }
@@ -174,9 +589,10 @@ impl MalVal {
             List(_, ref mut meta)
             | Vector(_, ref mut meta)
             | Hash(_, ref mut meta)
+            | Set(_, ref mut meta)
             | Func(_, ref mut meta)
-            | MalFunc { ref mut meta, .. } => {
-                *meta = Rc::new((&*new_meta).clone());
+            | MalFunc(_, ref mut meta) => {
+                *meta = Rc::new((*new_meta).clone());
             }
             _ => return error("with-meta not supported by type"),
         };
@@ -190,51 +606,198 @@ impl PartialEq for MalVal {
             (Nil, Nil) => true,
             (Bool(ref a), Bool(ref b)) => a == b,
             (Int(ref a), Int(ref b)) => a == b,
+            (BigInt(ref a), BigInt(ref b)) => a == b,
+            (Ratio(ref a), Ratio(ref b)) => a == b,
+            (Float(ref a), Float(ref b)) => a == b,
             (Str(ref a), Str(ref b)) => a == b,
+            (Char(ref a), Char(ref b)) => a == b,
             (Sym(ref a), Sym(ref b)) => a == b,
+            (Keyword(ref a), Keyword(ref b)) => Rc::ptr_eq(a, b),
             (List(ref a, _), List(ref b, _))
             | (Vector(ref a, _), Vector(ref b, _))
             | (List(ref a, _), Vector(ref b, _))
             | (Vector(ref a, _), List(ref b, _)) => a == b,
             (Hash(ref a, _), Hash(ref b, _)) => a == b,
-            (MalFunc { .. }, MalFunc { .. }) => false,
+            (Set(ref a, _), Set(ref b, _)) => a == b,
+            (LazySeq(_), _) | (_, LazySeq(_)) => {
+                self.sequential_q() && other.sequential_q() && seq_eq(self, other)
+            }
+            (Func(ref a, _), Func(ref b, _)) => Rc::ptr_eq(&a.f, &b.f),
+            (Regex(ref a), Regex(ref b)) => Rc::ptr_eq(a, b),
+            (Tagged(ref t1, ref a), Tagged(ref t2, ref b)) => t1 == t2 && a == b,
+            (Position(ref a), Position(ref b)) => a == b,
+            // atoms and closures are equal only to themselves, as they hash
+            (Atom(ref a), Atom(ref b)) => Rc::ptr_eq(a, b),
+            (MalFunc(ref a, _), MalFunc(ref b, _)) => {
+                Rc::ptr_eq(&a.ast, &b.ast) && Rc::ptr_eq(&a.env, &b.env) && a.is_macro == b.is_macro
+            }
             _ => false,
         }
     }
 }
 
-pub fn func(f: fn(MalArgs) -> MalRet) -> MalVal {
-    Func(f, Rc::new(Nil))
-}
+impl Eq for MalVal {}
 
-pub fn _assoc(mut hm: FnvHashMap<String, MalVal>, kvs: MalArgs) -> MalRet {
-    if kvs.len() % 2 != 0 {
-        return error("odd number of elements");
+// Steps through both sequences together, realizing no more of a lazy one
+// than it takes to find a difference, so one may be infinite
+fn seq_eq(a: &MalVal, b: &MalVal) -> bool {
+    let (mut a, mut b) = (a.clone(), b.clone());
+    loop {
+        match (a.seq_step(), b.seq_step()) {
+            (Ok(Some((x, rest_a))), Ok(Some((y, rest_b)))) if x == y => {
+                a = rest_a;
+                b = rest_b;
+            }
+            (Ok(None), Ok(None)) => return true,
+            _ => return false,
+        }
     }
-    for (k, v) in kvs.iter().tuples() {
-        match k {
+}
+
+// Must agree with PartialEq: lists and vectors with the same elements
+// hash the same, and map hashes do not depend on iteration order
+impl std::hash::Hash for MalVal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Nil => state.write_u8(0),
+            Bool(b) => {
+                state.write_u8(1);
+                b.hash(state);
+            }
+            Int(i) => {
+                state.write_u8(2);
+                i.hash(state);
+            }
+            BigInt(b) => {
+                state.write_u8(2);
+                b.hash(state);
+            }
+            Ratio(r) => {
+                state.write_u8(3);
+                r.hash(state);
+            }
+            Float(f) => {
+                state.write_u8(4);
+                // 0.0 and -0.0 are equal so they must hash the same
+                let f = if *f == 0.0 { 0.0 } else { *f };
+                f.to_bits().hash(state);
+            }
             Str(s) => {
-                hm.insert(s.to_string(), v.clone());
+                state.write_u8(5);
+                s.hash(state);
+            }
+            Sym(s) => {
+                state.write_u8(6);
+                s.hash(state);
+            }
+            Char(c) => {
+                state.write_u8(14);
+                c.hash(state);
+            }
+            Keyword(k) => {
+                // hash the name rather than the pointer so that map
+                // iteration order stays the same from run to run
+                state.write_u8(13);
+                k.hash(state);
+            }
+            List(l, _) | Vector(l, _) => {
+                state.write_u8(7);
+                l.iter().for_each(|v| v.hash(state));
+            }
+            LazySeq(_) => {
+                state.write_u8(7);
+                // an unrealizable seq equals nothing, so any hash will do
+                if let Ok(v) = self.seq_vec() {
+                    v.iter().for_each(|v| v.hash(state));
+                }
+            }
+            Hash(hm, _) => {
+                state.write_u8(8);
+                let mut acc: u64 = 0;
+                for (k, v) in hm.iter() {
+                    let mut h = FnvHasher::default();
+                    k.hash(&mut h);
+                    v.hash(&mut h);
+                    acc = acc.wrapping_add(h.finish());
+                }
+                state.write_u64(acc);
+            }
+            Set(hs, _) => {
+                state.write_u8(12);
+                let mut acc: u64 = 0;
+                for e in hs.iter() {
+                    let mut h = FnvHasher::default();
+                    e.hash(&mut h);
+                    acc = acc.wrapping_add(h.finish());
+                }
+                state.write_u64(acc);
+            }
+            Func(nf, _) => {
+                state.write_u8(9);
+                (Rc::as_ptr(&nf.f) as *const u8).hash(state);
+            }
+            MalFunc(c, _) => {
+                state.write_u8(10);
+                (&*c.ast as *const MalVal).hash(state);
+            }
+            Atom(a) => {
+                state.write_u8(11);
+                (&**a as *const RefCell<MalVal>).hash(state);
+            }
+            Regex(r) => {
+                state.write_u8(15);
+                (&**r as *const regex::Regex).hash(state);
+            }
+            Tagged(t, v) => {
+                state.write_u8(16);
+                t.hash(state);
+                v.hash(state);
+            }
+            Position(p) => {
+                state.write_u8(17);
+                p.hash(state);
             }
-            _ => return error("key is not string"),
         }
     }
-    Ok(Hash(Rc::new(hm), Rc::new(Nil)))
 }
 
-pub fn _dissoc(mut hm: FnvHashMap<String, MalVal>, ks: MalArgs) -> MalRet {
+// An anonymous builtin taking any number of arguments
+pub fn func<F: Fn(MalArgs) -> MalRet + 'static>(f: F) -> MalVal {
+    native_fn("", Arity::at_least(0), f)
+}
+
+pub fn native_fn<F: Fn(MalArgs) -> MalRet + 'static>(name: &str, arity: Arity, f: F) -> MalVal {
+    Func(
+        Rc::new(NativeFn {
+            name: Rc::from(name),
+            arity,
+            f: Rc::new(f),
+        }),
+        Rc::new(Nil),
+    )
+}
+
+pub fn _assoc(mut hm: MalMap, meta: Rc<MalVal>, kvs: MalArgs) -> MalRet {
+    if !kvs.len().is_multiple_of(2) {
+        return error("odd number of elements");
+    }
+    for (k, v) in kvs.iter().tuples() {
+        hm.insert(k.clone(), v.clone());
+    }
+    Ok(Hash(hm, meta))
+}
+
+pub fn _dissoc(mut hm: MalMap, meta: Rc<MalVal>, ks: MalArgs) -> MalRet {
     for k in ks.iter() {
-        match k {
-            Str(ref s) => {
-                hm.remove(s);
-            }
-            _ => return error("key is not string"),
-        }
+        hm.remove(k);
     }
-    Ok(Hash(Rc::new(hm), Rc::new(Nil)))
+    Ok(Hash(hm, meta))
+}
+
+pub fn hash_set(vs: MalArgs) -> MalRet {
+    Ok(Set(vs.into_iter().collect(), Rc::new(Nil)))
 }
 
 pub fn hash_map(kvs: MalArgs) -> MalRet {
-    let hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
-    _assoc(hm, kvs)
+    _assoc(MalMap::default(), Rc::new(Nil), kvs)
 }